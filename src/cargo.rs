@@ -0,0 +1,206 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::filter::Filter;
+use crate::watchers::{Traversal, Watcher, WatcherBuilder};
+
+/// The directories below a package root whose changes trigger a rebuild.
+pub const SOURCE_DIRS: &[&str] = &["src", "tests", "benches", "examples"];
+
+/// The files of a package whose changes trigger a rebuild.
+pub const SOURCE_FILES: &[&str] = &["Cargo.toml", "Cargo.lock", "build.rs"];
+
+/// A Cargo package or workspace, found by its `Cargo.toml`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Project {
+    root: PathBuf,
+}
+
+impl Project {
+    /// Finds the closest directory containing a `Cargo.toml`, starting at
+    /// `dir` and continuing with its parents.
+    pub fn find<P: AsRef<Path>>(dir: P) -> Option<Project> {
+        dir.as_ref()
+           .ancestors()
+           .find(|dir| dir.join("Cargo.toml").is_file())
+           .map(|root| Project { root: root.to_path_buf() })
+    }
+
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// A recursive watcher for the project's sources, see `SOURCE_DIRS` and
+    /// `SOURCE_FILES`. `target/` is not watched.
+    pub fn watcher(&self) -> WatcherBuilder {
+        Watcher::builder(&self.root)
+            .traversal(Traversal::RECURSIVE)
+            .ignore_dir("target")
+            .filter(Filter::custom(|event| is_source(event.relative_path())))
+    }
+
+    /// The command running `cargo <subcommand>` with `args`, reporting
+    /// diagnostics in a format `Diagnostic::parse` understands.
+    pub fn command(&self, subcommand: &str, args: &[String]) -> Vec<String> {
+        let mut command = vec![
+            String::from("cargo"),
+            String::from(subcommand),
+            String::from("--message-format=short"),
+            format!("--manifest-path={}", self.root.join("Cargo.toml").display()),
+        ];
+
+        command.extend_from_slice(args);
+        command
+    }
+}
+
+// Whether a path relative to the package root, or to a workspace member
+// inside it, is one of its sources.
+fn is_source(path: &Path) -> bool {
+    let names: Vec<&str> = path.iter().filter_map(|name| name.to_str()).collect();
+
+    let in_source_dir = names.iter().any(|name| SOURCE_DIRS.contains(name));
+    let is_source_file = names.last().is_some_and(|name| SOURCE_FILES.contains(name));
+
+    (in_source_dir || is_source_file) && !names.contains(&"target")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A compiler error or warning, as printed by `cargo --message-format=short`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The error code, e.g. `E0308`, if any.
+    pub code: Option<String>,
+    pub message: String,
+    /// The file, line and column the diagnostic points at, if any.
+    pub location: Option<(PathBuf, u32, u32)>,
+}
+
+impl Diagnostic {
+    /// Parses a line like `src/main.rs:3:5: error[E0308]: mismatched types`,
+    /// or one without a location like `error: could not compile`.
+    pub fn parse(line: &str) -> Option<Diagnostic> {
+        let (location, rest) = match split_location(line) {
+            Some((location, rest)) => (Some(location), rest),
+            None => (None, line),
+        };
+
+        let (label, message) = rest.split_once(": ")?;
+
+        let (severity, code) = match label.split_once('[') {
+            Some((severity, code)) => (severity, Some(String::from(code.strip_suffix(']')?))),
+            None => (label, None),
+        };
+
+        let severity = match severity {
+            "error" => Severity::Error,
+            "warning" => Severity::Warning,
+            _ => return None,
+        };
+
+        Some(Diagnostic {
+            severity,
+            code,
+            message: String::from(message),
+            location,
+        })
+    }
+
+    /// Parses every diagnostic in cargo's output and skips everything else.
+    pub fn parse_all(output: &str) -> Vec<Diagnostic> {
+        output.lines().filter_map(Diagnostic::parse).collect()
+    }
+}
+
+// Splits `path:line:column: rest` into the location and the rest.
+fn split_location(line: &str) -> Option<((PathBuf, u32, u32), &str)> {
+    let (location, rest) = line.split_once(": ")?;
+    let mut parts = location.rsplitn(3, ':');
+
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let path = parts.next()?;
+
+    Some(((PathBuf::from(path), line, column), rest))
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((path, line, column)) = &self.location {
+            write!(f, "{}:{}:{}: ", path.display(), line, column)?;
+        }
+
+        match self.severity {
+            Severity::Error => write!(f, "error")?,
+            Severity::Warning => write!(f, "warning")?,
+        }
+
+        if let Some(code) = &self.code {
+            write!(f, "[{}]", code)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn sources_exclude_target() {
+        assert!(is_source(Path::new("src/main.rs")));
+        assert!(is_source(Path::new("Cargo.toml")));
+        assert!(is_source(Path::new("crates/core/tests/it.rs")));
+        assert!(is_source(Path::new("crates/core/build.rs")));
+
+        assert!(!is_source(Path::new("README.md")));
+        assert!(!is_source(Path::new("docs/src.md")));
+        assert!(!is_source(Path::new("target/debug/build/x/out/src/gen.rs")));
+        assert!(!is_source(Path::new("target/package/x/Cargo.toml")));
+    }
+
+    #[test]
+    fn find_uses_the_closest_manifest() {
+        let dir = env::temp_dir().join(format!("aa-cargo-{}", process::id()));
+        fs::create_dir_all(dir.join("member/src")).unwrap();
+        fs::write(dir.join("Cargo.toml"), "").unwrap();
+        fs::write(dir.join("member/Cargo.toml"), "").unwrap();
+
+        assert_eq!(Project::find(dir.join("member/src")).unwrap().root(), dir.join("member"));
+        assert_eq!(Project::find(&dir).unwrap().root(), dir);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_diagnostics_and_prints_them_back() {
+        let output = "\
+   Compiling aa v0.1.0
+src/main.rs:3:5: error[E0308]: mismatched types
+src/lib.rs:10:1: warning: unused import: `std::fs`
+error: could not compile `aa` (bin \"aa\") due to 1 previous error
+";
+
+        let diagnostics = Diagnostic::parse_all(output);
+
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0], Diagnostic {
+            severity: Severity::Error,
+            code: Some(String::from("E0308")),
+            message: String::from("mismatched types"),
+            location: Some((PathBuf::from("src/main.rs"), 3, 5)),
+        });
+        assert_eq!(diagnostics[2].location, None);
+
+        let printed: Vec<String> = diagnostics.iter().map(Diagnostic::to_string).collect();
+        assert_eq!(printed, output.lines().skip(1).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use notify::event::{CreateKind, DataChange, Flag, ModifyKind, RemoveKind};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+
+use crate::error::WatcherError;
+use crate::events::{EventKind, WatchEvent};
+use crate::watchers::{HiddenPolicy, Traversal, Watcher};
+
+// How often a watch thread checks whether its path was unwatched.
+const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Implements the `notify` crate's `Watcher` trait with a `Watcher` per
+/// watched path, each read on its own thread, so code written against
+/// `notify` can switch backends.
+///
+/// `RecursiveMode::NonRecursive` watches a directory without any
+/// subdirectories. Hidden files and directories are watched, like `notify`
+/// does. The `Config` is ignored.
+pub struct NotifyWatcher {
+    handler: Arc<Mutex<dyn EventHandler>>,
+    watches: HashMap<PathBuf, Watch>,
+}
+
+struct Watch {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Watch {
+    fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        let _ = self.thread.join();
+    }
+}
+
+impl notify::Watcher for NotifyWatcher {
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<NotifyWatcher> {
+        Ok(NotifyWatcher {
+            handler: Arc::new(Mutex::new(event_handler)),
+            watches: HashMap::new(),
+        })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        let watcher = if path.is_dir() {
+            let builder = Watcher::builder(path).hidden(HiddenPolicy::IncludeAll);
+
+            match recursive_mode {
+                RecursiveMode::Recursive => builder.traversal(Traversal::RECURSIVE),
+                RecursiveMode::NonRecursive => builder.traversal(Traversal::RECURSIVE).max_depth(0),
+            }.build()
+        } else {
+            Watcher::file_watcher(path)
+        };
+
+        let mut watcher = watcher.map_err(|e| notify_error(e, path))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = self.handler.clone();
+        let stopped = stop.clone();
+
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                let event = match watcher.next_event_timeout(STOP_INTERVAL) {
+                    Ok(Some(event)) => Ok(notify_event(&event)),
+                    Ok(None) => continue,
+                    Err(e) => Err(notify::Error::generic(&e.to_string())),
+                };
+                let failed = event.is_err();
+
+                handler.lock().unwrap().handle_event(event);
+
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        if let Some(watch) = self.watches.insert(path.to_path_buf(), Watch { stop, thread }) {
+            watch.stop();
+        }
+
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self.watches.remove(path) {
+            Some(watch) => {
+                watch.stop();
+
+                Ok(())
+            },
+            None => Err(notify::Error::watch_not_found().add_path(path.to_path_buf())),
+        }
+    }
+
+    fn kind() -> WatcherKind { WatcherKind::Inotify }
+}
+
+impl Drop for NotifyWatcher {
+    fn drop(&mut self) {
+        for (_, watch) in self.watches.drain() {
+            watch.stop();
+        }
+    }
+}
+
+fn notify_event(event: &WatchEvent) -> notify::Event {
+    let kind = match (event.kind, event.is_dir) {
+        (EventKind::Created, true) => notify::EventKind::Create(CreateKind::Folder),
+        (EventKind::Created, false) => notify::EventKind::Create(CreateKind::File),
+        (EventKind::Modified, _) => notify::EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+        (EventKind::Deleted, true) => notify::EventKind::Remove(RemoveKind::Folder),
+        (EventKind::Deleted, false) => notify::EventKind::Remove(RemoveKind::File),
+        (EventKind::Rescan, _) | (EventKind::Recovered, _) => notify::EventKind::Other,
+        (EventKind::Storm, _) | (EventKind::Timer, _) => notify::EventKind::Any,
+    };
+
+    let mut notify_event = notify::Event::new(kind).add_path(event.path.clone());
+
+    if let Some(storm) = &event.storm {
+        notify_event.paths.extend(storm.dirs.iter().cloned());
+    }
+
+    match event.kind {
+        EventKind::Rescan | EventKind::Recovered => notify_event.set_flag(Flag::Rescan),
+        _ => notify_event,
+    }
+}
+
+fn notify_error(error: WatcherError, path: &Path) -> notify::Error {
+    let error = match error {
+        WatcherError::WatchLimit { .. } => notify::Error::new(notify::ErrorKind::MaxFilesWatch),
+        WatcherError::PathNotFound(_) => notify::Error::path_not_found(),
+        WatcherError::Init(e) | WatcherError::Io(e) => notify::Error::io(e),
+        e => notify::Error::generic(&e.to_string()),
+    };
+
+    error.add_path(path.to_path_buf())
+}
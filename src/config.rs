@@ -0,0 +1,140 @@
+#[cfg(feature = "config")]
+use std::fs;
+#[cfg(feature = "config")]
+use std::io;
+#[cfg(feature = "config")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Settings read from a TOML file, which can be changed while `aa` runs,
+/// e.g.
+///
+/// ```toml
+/// command = ["cargo", "run"]
+/// then = ["./smoke-test.sh"]
+/// files = ["../shared/settings.json"]
+/// ignore = ["*.log", "tmp/**"]
+/// ```
+///
+/// Loading one from a file requires the `config` feature.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(default, deny_unknown_fields))]
+pub struct Config {
+    /// Replaces the command given on the command line.
+    pub command: Option<Vec<String>>,
+    /// Shell commands run after the command, like `--then`.
+    pub then: Vec<String>,
+    /// Files watched directly, like `--watch-file`. Relative paths are
+    /// relative to the directory of the config file.
+    pub files: Vec<PathBuf>,
+    /// Globs of paths whose changes are ignored, relative to the watched
+    /// root.
+    pub ignore: Vec<String>,
+}
+
+impl Config {
+    #[cfg(feature = "config")]
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let mut config: Config = toml::from_str(&fs::read_to_string(path)?).map_err(invalid)?;
+
+        if config.command.as_ref().is_some_and(Vec::is_empty) {
+            return Err(invalid("The command is empty"));
+        }
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        for file in &mut config.files {
+            *file = dir.join(&*file);
+        }
+
+        Ok(config)
+    }
+
+    /// What changed from this config to `newer`.
+    pub fn diff(&self, newer: &Config) -> ConfigDiff {
+        ConfigDiff {
+            added_files: newer.files.iter().filter(|file| !self.files.contains(file)).cloned().collect(),
+            removed_files: self.files.iter().filter(|file| !newer.files.contains(file)).cloned().collect(),
+            ignore_changed: self.ignore != newer.ignore,
+            command_changed: self.command != newer.command || self.then != newer.then,
+        }
+    }
+}
+
+/// The changes between two versions of a `Config`, to apply them without
+/// restarting.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added_files: Vec<PathBuf>,
+    pub removed_files: Vec<PathBuf>,
+    pub ignore_changed: bool,
+    /// The command or the steps after it changed.
+    pub command_changed: bool,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool { *self == ConfigDiff::default() }
+}
+
+#[cfg(feature = "config")]
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(command: &[&str], files: &[&str]) -> Config {
+        Config {
+            command: Some(command.iter().map(|s| s.to_string()).collect()),
+            files: files.iter().map(PathBuf::from).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn diff_reports_changed_files_and_commands() {
+        let old = config(&["cargo", "run"], &["a", "b"]);
+
+        assert!(old.diff(&old.clone()).is_empty());
+
+        let diff = old.diff(&config(&["cargo", "run"], &["b", "c"]));
+        assert_eq!(diff.added_files, [PathBuf::from("c")]);
+        assert_eq!(diff.removed_files, [PathBuf::from("a")]);
+        assert!(!diff.command_changed);
+
+        let diff = old.diff(&config(&["cargo", "test"], &["a", "b"]));
+        assert!(diff.added_files.is_empty() && diff.removed_files.is_empty());
+        assert!(diff.command_changed);
+
+        let diff = old.diff(&Config { then: vec!["./smoke-test.sh".to_string()], ..old.clone() });
+        assert!(diff.command_changed && !diff.ignore_changed);
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn load_resolves_files_next_to_the_config() {
+        use std::env;
+        use std::process;
+
+        let dir = env::temp_dir().join(format!("aa-config-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("aa.toml");
+        fs::write(&path, "command = [\"make\"]\nfiles = [\"../shared.json\"]\n").unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.command, Some(vec!["make".to_string()]));
+        assert_eq!(config.files, [dir.join("../shared.json")]);
+
+        fs::write(&path, "command = []\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::write(&path, "comand = [\"make\"]\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
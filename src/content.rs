@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::events::{EventKind, WatchEvent};
+
+/// Files larger than this are not hashed unless configured otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+// The parameters of 64-bit FNV-1a.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Suppresses `Modified` events for files whose content did not change, e.g.
+/// because a build tool only touched them.
+///
+/// A file's first event is always emitted, since there is nothing to compare
+/// it to. Files larger than `max_size` are never hashed and always emitted.
+/// Contents are hashed with 64-bit FNV-1a, which does not depend on the
+/// Rust release.
+pub struct ContentCache {
+    max_size: u64,
+    hashes: HashMap<PathBuf, u64>,
+}
+
+impl ContentCache {
+    pub fn new(max_size: u64) -> ContentCache {
+        ContentCache {
+            max_size,
+            hashes: HashMap::new(),
+        }
+    }
+
+    /// Whether the event reflects an actual change and should be emitted.
+    pub fn changed(&mut self, event: &WatchEvent) -> bool {
+        match event.kind {
+            // Lost events may have changed anything, so hashes are no longer trusted.
+            EventKind::Rescan | EventKind::Recovered => {
+                self.hashes.clear();
+
+                true
+            },
+            EventKind::Storm | EventKind::Timer => true,
+            EventKind::Deleted if event.is_dir => {
+                self.hashes.retain(|path, _| !path.starts_with(&event.path));
+
+                true
+            },
+            EventKind::Deleted => {
+                self.hashes.remove(&event.path);
+
+                true
+            },
+            EventKind::Created | EventKind::Modified if event.is_dir => true,
+            EventKind::Created | EventKind::Modified => {
+                let hash = match self.hash(&event.path) {
+                    Ok(Some(hash)) => hash,
+                    // Unreadable or too large to hash, so assume it changed.
+                    _ => {
+                        self.hashes.remove(&event.path);
+
+                        return true;
+                    },
+                };
+
+                self.hashes.insert(event.path.clone(), hash) != Some(hash)
+            },
+        }
+    }
+
+    fn hash(&self, path: &Path) -> io::Result<Option<u64>> {
+        if fs::metadata(path)?.len() > self.max_size {
+            return Ok(None);
+        }
+
+        let mut file = File::open(path)?;
+        let mut hash = FNV_OFFSET;
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(Some(hash)),
+                n => hash = buffer[..n].iter().fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use crate::source::EventSource;
+    use std::{env, process};
+
+    #[test]
+    fn suppresses_files_touched_without_changing() {
+        let dir = env::temp_dir().join(format!("aa-content-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), "old").unwrap();
+
+        let mut mock = MockWatcher::new(&dir);
+        mock.modify("a").modify("a").modify("a").rescan().modify("a");
+
+        let mut cache = ContentCache::new(DEFAULT_MAX_SIZE);
+        let mut next = || cache.changed(&mock.next_event().unwrap());
+
+        assert!(next(), "the first event has nothing to compare to");
+        assert!(!next(), "touched without changing");
+
+        fs::write(dir.join("a"), "new").unwrap();
+        assert!(next());
+
+        assert!(next(), "rescans are passed on");
+        assert!(next(), "hashes are forgotten after a rescan");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_with_fnv_1a() {
+        let path = env::temp_dir().join(format!("aa-content-fnv-{}", process::id()));
+        fs::write(&path, "a").unwrap();
+
+        assert_eq!(ContentCache::new(DEFAULT_MAX_SIZE).hash(&path).unwrap(), Some(0xaf63_dc4c_8601_ec8c));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::io;
+use std::process::{Command, Stdio};
+
+use crate::events::WatchEvent;
+use crate::runner::Status;
+
+/// Shows a desktop notification through libnotify's `notify-send`, which
+/// has to be installed at runtime; aa does not link against libnotify.
+pub fn notify(summary: &str, body: &str) -> io::Result<()> {
+    let status = Command::new("notify-send")
+        .arg("--app-name=aa")
+        .arg("--urgency=critical")
+        .arg(summary)
+        .arg(body)
+        .stdin(Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("notify-send exited with {}", status)))
+    }
+}
+
+/// Notifies about a failed command with its exit status and the file that
+/// triggered it, for `RunnerBuilder::on_exit`. Successful runs are ignored.
+pub fn notify_failure(status: Status, events: &[WatchEvent]) {
+    let outcome = match status {
+        Status::Exited(status) if status.success() => return,
+        Status::Exited(status) => match status.code() {
+            Some(code) => format!("exited with code {}", code),
+            None => String::from("was killed by a signal"),
+        },
+        Status::Failed => String::from("could not be started"),
+        Status::TimedOut => String::from("timed out"),
+        Status::Idle | Status::Running => return,
+    };
+
+    let body = match events.first() {
+        Some(event) => format!("The command {} after {} was {}", outcome, event.relative_path().display(), event.kind.name()),
+        None => format!("The command {}", outcome),
+    };
+
+    // Nobody to tell if notifications cannot be shown.
+    let _ = notify("Command failed", &body);
+}
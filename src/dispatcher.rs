@@ -0,0 +1,272 @@
+use std::path::Path;
+use std::slice;
+use std::thread;
+
+use crate::error::WatcherError;
+use crate::events::{EventKind, WatchEvent};
+use crate::filter::Filter;
+use crate::journal::{Entry, Journal};
+use crate::source::EventSource;
+use crate::watchers::Watcher;
+
+/// Returned by hooks to tell the dispatcher whether to keep watching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    Continue,
+    Stop,
+}
+
+type Hook = Box<dyn FnMut(&WatchEvent) -> Control>;
+
+type BatchHook = Box<dyn FnMut(&[WatchEvent]) -> Control>;
+
+struct Route {
+    filter: Filter,
+    priority: i32,
+    hook: BatchHook,
+}
+
+/// Runs closures registered per event kind for every event of a `Watcher`,
+/// or of another `EventSource` such as a `MockWatcher`, and routes for the
+/// events matching a filter.
+///
+/// Events are dispatched in batches, as read by `EventSource::next_batch`.
+/// For each batch:
+///
+/// 1. The hooks registered per kind run for every event, in the order the
+///    events were reported and, for each event, the order the hooks were
+///    registered.
+/// 2. Every route any event of the batch matches runs once, with those
+///    events in the order they were reported. Routes with a higher priority
+///    run first, routes with the same priority in the order they were
+///    registered, e.g. a route applying `schema.sql` with priority 1 before
+///    one rebuilding `src/**/*.rs` with priority 0.
+///
+/// Everything runs on the thread calling `run`. Once a hook or route returns
+/// `Control::Stop`, the ones after it are skipped and `run` returns.
+pub struct Dispatcher<S: EventSource = Watcher> {
+    watcher: S,
+    // The kind each hook is for, or `None` for all of them.
+    hooks: Vec<(Option<EventKind>, Hook)>,
+    // Sorted by descending priority, then in order of registration.
+    routes: Vec<Route>,
+}
+
+impl<S: EventSource> Dispatcher<S> {
+    pub fn new(watcher: S) -> Dispatcher<S> {
+        Dispatcher {
+            watcher,
+            hooks: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn on_create<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Created), hook)
+    }
+
+    pub fn on_modify<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Modified), hook)
+    }
+
+    pub fn on_delete<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Deleted), hook)
+    }
+
+    /// Runs the hook after events were lost. See `EventKind::Rescan`.
+    pub fn on_rescan<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Rescan), hook)
+    }
+
+    /// Runs the hook after a supervised watcher recovered from an error. See
+    /// `EventKind::Recovered`.
+    pub fn on_recovered<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Recovered), hook)
+    }
+
+    /// Runs the hook for bursts of changes. See `WatcherBuilder::storm`.
+    pub fn on_storm<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Storm), hook)
+    }
+
+    /// Runs the hook on a schedule. See `Scheduler`.
+    pub fn on_timer<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(Some(EventKind::Timer), hook)
+    }
+
+    pub fn on_any<F>(self, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hook(None, hook)
+    }
+
+    fn hook<F>(mut self, kind: Option<EventKind>, hook: F) -> Dispatcher<S>
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        self.hooks.push((kind, Box::new(hook)));
+        self
+    }
+
+    /// Runs `hook` once per batch with the events matching `filter`, before
+    /// the routes with a lower `priority`. See `Dispatcher` for the order.
+    pub fn route<F>(mut self, filter: Filter, priority: i32, hook: F) -> Dispatcher<S>
+        where F: FnMut(&[WatchEvent]) -> Control + 'static {
+        let at = self.routes.iter().position(|route| route.priority < priority).unwrap_or(self.routes.len());
+
+        self.routes.insert(at, Route {
+            filter,
+            priority,
+            hook: Box::new(hook),
+        });
+        self
+    }
+
+    /// Blocks, running the hooks and routes for every batch, until one of
+    /// them returns `Control::Stop`, the watcher fails, or it is finished,
+    /// see `EventSource::is_finished`.
+    pub fn run(&mut self) -> Result<(), WatcherError> {
+        while !self.watcher.is_finished() {
+            let batch = self.watcher.next_batch()?;
+
+            if self.dispatch_batch(&batch) == Control::Stop {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the hooks and routes for the event as if the watcher had
+    /// reported it on its own. Returns `Control::Stop` if one of them did.
+    pub fn dispatch(&mut self, event: &WatchEvent) -> Control { self.dispatch_batch(slice::from_ref(event)) }
+
+    /// Runs the hooks and routes for the events as if the watcher had
+    /// reported them in one batch. Returns `Control::Stop` if one of them did.
+    pub fn dispatch_batch(&mut self, batch: &[WatchEvent]) -> Control {
+        for event in batch {
+            for (_, hook) in self.hooks.iter_mut().filter(|(kind, _)| kind.map_or(true, |k| k == event.kind)) {
+                if hook(event) == Control::Stop {
+                    return Control::Stop;
+                }
+            }
+        }
+
+        for route in &mut self.routes {
+            let matched: Vec<WatchEvent> = batch.iter().filter(|event| route.filter.matches(event)).cloned().collect();
+
+            if !matched.is_empty() && (route.hook)(&matched) == Control::Stop {
+                return Control::Stop;
+            }
+        }
+
+        Control::Continue
+    }
+
+    /// Runs the hooks and routes for every event recorded in a `Journal`, in
+    /// order and without waiting in between, until one of them returns
+    /// `Control::Stop`. Every event is dispatched as a batch of its own.
+    pub fn replay<P: AsRef<Path>>(&mut self, journal: P) -> Result<(), WatcherError> {
+        self.replay_entries(Journal::read(journal)?, false)
+    }
+
+    /// Like `replay`, but waits between two events as long as passed between
+    /// them when they were recorded, e.g. to reproduce debouncing.
+    pub fn replay_paced<P: AsRef<Path>>(&mut self, journal: P) -> Result<(), WatcherError> {
+        self.replay_entries(Journal::read(journal)?, true)
+    }
+
+    fn replay_entries(&mut self, entries: Vec<Entry>, paced: bool) -> Result<(), WatcherError> {
+        let mut last = None;
+
+        for entry in entries {
+            if let (true, Some(last)) = (paced, last) {
+                thread::sleep(entry.elapsed.saturating_sub(last));
+            }
+
+            last = Some(entry.elapsed);
+
+            if self.dispatch(&entry.event) == Control::Stop {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn watcher(&mut self) -> &mut S { &mut self.watcher }
+
+    pub fn into_watcher(self) -> S { self.watcher }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn log() -> Rc<RefCell<Vec<String>>> { Rc::new(RefCell::new(Vec::new())) }
+
+    #[test]
+    fn run_returns_once_the_mock_is_finished() {
+        let mut mock = MockWatcher::new("/project");
+        mock.create("a").modify("a").end_batch().delete("b");
+
+        let seen = log();
+        let (created, any) = (seen.clone(), seen.clone());
+
+        Dispatcher::new(mock)
+            .on_create(move |event| {
+                created.borrow_mut().push(format!("create {}", event.relative_path().display()));
+                Control::Continue
+            })
+            .on_any(move |event| {
+                any.borrow_mut().push(format!("{} {}", event.kind.name(), event.relative_path().display()));
+                Control::Continue
+            })
+            .run()
+            .unwrap();
+
+        assert_eq!(*seen.borrow(), ["create a", "created a", "modified a", "deleted b"]);
+    }
+
+    #[test]
+    fn routes_run_once_per_batch_by_priority() {
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("src/main.rs").modify("schema.sql").modify("src/lib.rs").end_batch().modify("schema.sql");
+
+        let seen = log();
+        let (build, migrate) = (seen.clone(), seen.clone());
+
+        Dispatcher::new(mock)
+            .route(Filter::ext("rs"), 0, move |events| {
+                build.borrow_mut().push(format!("build {}", events.len()));
+                Control::Continue
+            })
+            .route(Filter::ext("sql"), 1, move |events| {
+                migrate.borrow_mut().push(format!("migrate {}", events.len()));
+                Control::Continue
+            })
+            .run()
+            .unwrap();
+
+        assert_eq!(*seen.borrow(), ["migrate 1", "build 2", "migrate 1"]);
+    }
+
+    #[test]
+    fn stop_ends_run_early() {
+        let mut mock = MockWatcher::new("/project");
+        mock.create("a").end_batch().create("b");
+
+        let mut dispatcher = Dispatcher::new(mock).on_create(|_| Control::Stop);
+
+        dispatcher.run().unwrap();
+
+        assert_eq!(dispatcher.watcher().len(), 1);
+    }
+}
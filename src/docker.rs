@@ -0,0 +1,194 @@
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::events::{EventKind, WatchEvent};
+
+/// A running container that changed files are copied into with `docker cp`,
+/// for developing on the host while running in the container.
+///
+/// Paths are mapped by the longest matching host directory given to `map`.
+/// Deleted paths are removed in the container, and the whole mapped tree is
+/// copied for `Rescan`, `Recovered` and `Storm` events, without removing
+/// anything.
+#[derive(Clone, Debug)]
+pub struct Container {
+    name: String,
+    program: String,
+    // Absolute host directories and the container paths they map to.
+    mappings: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Container {
+    pub fn new(name: &str) -> Container {
+        Container {
+            name: String::from(name),
+            program: String::from("docker"),
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Runs another program with docker's command line, e.g. `podman`.
+    pub fn program(mut self, program: &str) -> Container {
+        self.program = String::from(program);
+        self
+    }
+
+    /// Copies changes below the host directory `host` to `container` inside
+    /// the container. Can be called repeatedly.
+    pub fn map<P: AsRef<Path>, Q: AsRef<Path>>(mut self, host: P, container: Q) -> Container {
+        self.mappings.push((absolute(host.as_ref()), container.as_ref().to_path_buf()));
+        self
+    }
+
+    /// The path inside the container `path` maps to, if any.
+    pub fn target(&self, path: &Path) -> Option<PathBuf> {
+        let path = absolute(path);
+
+        self.mappings.iter()
+            .filter(|(host, _)| path.starts_with(host))
+            .max_by_key(|(host, _)| host.components().count())
+            .map(|(host, container)| container.join(path.strip_prefix(host).unwrap()))
+    }
+
+    /// The command line running the shell command `command` inside the
+    /// container, e.g. to have a server reload after copying.
+    pub fn exec_command(&self, command: &str) -> Vec<String> {
+        [self.program.as_str(), "exec", &self.name, "sh", "-c", command].iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Applies the event inside the container. Events for unmapped paths are
+    /// ignored.
+    pub fn sync(&self, event: &WatchEvent) -> io::Result<()> {
+        if let EventKind::Rescan | EventKind::Recovered | EventKind::Storm = event.kind {
+            let root = absolute(&event.root);
+
+            for (host, container) in &self.mappings {
+                if host.starts_with(&root) {
+                    self.copy(host, container, true)?;
+                } else if root.starts_with(host) {
+                    self.copy(&root, &container.join(root.strip_prefix(host).unwrap()), true)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let target = match self.target(&event.path) {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+
+        match event.kind {
+            EventKind::Created | EventKind::Modified if event.is_dir => self.copy(&event.path, &target, true),
+            EventKind::Created => {
+                // The file may be the first in a directory created meanwhile.
+                if let Some(parent) = target.parent() {
+                    self.exec(&[OsString::from("mkdir"), OsString::from("-p"), parent.into()])?;
+                }
+
+                self.copy(&event.path, &target, false)
+            },
+            EventKind::Modified => self.copy(&event.path, &target, false),
+            EventKind::Deleted => self.exec(&[OsString::from("rm"), OsString::from("-rf"), OsString::from("--"), target.into()]),
+            EventKind::Rescan | EventKind::Recovered | EventKind::Storm | EventKind::Timer => Ok(()),
+        }
+    }
+
+    // Copies `source` to `target`. With `tree`, the directory's content is
+    // copied into `target`, instead of the directory into a `target` that
+    // exists.
+    fn copy(&self, source: &Path, target: &Path, tree: bool) -> io::Result<()> {
+        let source = if tree { source.join(".") } else { source.to_path_buf() };
+
+        let mut destination = OsString::from(format!("{}:", self.name));
+        destination.push(target);
+
+        self.run(Command::new(&self.program).arg("cp").arg(source).arg(destination))
+    }
+
+    fn exec(&self, args: &[OsString]) -> io::Result<()> {
+        self.run(Command::new(&self.program).arg("exec").arg(&self.name).args(args))
+    }
+
+    fn run(&self, command: &mut Command) -> io::Result<()> {
+        let output = command.stdin(Stdio::null())
+                            .output()
+                            .map_err(|e| io::Error::new(e.kind(), format!("Failed to run {}: {}", self.program, e)))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            Err(io::Error::other(format!("{} exited with {}: {}", self.program, output.status, stderr.trim())))
+        }
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Origin;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    fn event(kind: EventKind, path: &str) -> WatchEvent {
+        WatchEvent {
+            kind,
+            path: PathBuf::from(path),
+            root: PathBuf::from("/project"),
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    #[test]
+    fn target_maps_by_the_longest_matching_directory() {
+        let container = Container::new("app")
+            .map("/project", "/app")
+            .map("/project/static", "/srv/static")
+            .map("/project/stat", "/srv/stat");
+
+        assert_eq!(container.target(Path::new("/project/src/main.rs")), Some(PathBuf::from("/app/src/main.rs")));
+        assert_eq!(container.target(Path::new("/project/static/logo.png")), Some(PathBuf::from("/srv/static/logo.png")));
+        assert_eq!(container.target(Path::new("/project/statistics")), Some(PathBuf::from("/app/statistics")));
+        assert_eq!(container.target(Path::new("/project")), Some(PathBuf::from("/app")));
+        assert_eq!(container.target(Path::new("/elsewhere/file")), None);
+    }
+
+    #[test]
+    fn sync_runs_docker_for_mapped_paths_only() {
+        let dir = env::temp_dir().join(format!("aa-docker-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let log = dir.join("log");
+        let program = dir.join("docker");
+        fs::write(&program, format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display())).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let container = Container::new("app").program(program.to_str().unwrap()).map("/project/src", "/app/src");
+
+        container.sync(&event(EventKind::Created, "/project/src/new.rs")).unwrap();
+        container.sync(&event(EventKind::Modified, "/project/README.md")).unwrap();
+        container.sync(&event(EventKind::Deleted, "/project/src/old.rs")).unwrap();
+
+        assert_eq!(fs::read_to_string(&log).unwrap(), "\
+exec app mkdir -p /app/src
+cp /project/src/new.rs app:/app/src/new.rs
+exec app rm -rf -- /app/src/old.rs
+");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+
+use crate::watchers;
+
+#[derive(Debug)]
+pub enum WatcherError {
+    /// The inotify instance could not be created.
+    Init(io::Error),
+    /// The per-user inotify watch limit was reached while adding `path`, after
+    /// `watched` of the `requested` watches were added. `limit` is the value
+    /// of `/proc/sys/fs/inotify/max_user_watches`, if it could be read.
+    WatchLimit { path: PathBuf, watched: usize, requested: usize, limit: Option<usize> },
+    /// The path to be watched does not exist.
+    PathNotFound(PathBuf),
+    /// The directory traversal failed, e.g. due to a permission error.
+    Walk(walkdir::Error),
+    /// Any other I/O error, e.g. while reading events.
+    Io(io::Error),
+}
+
+impl fmt::Display for WatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatcherError::Init(e) => write!(f, "Failed to initialize inotify: {}", e),
+            WatcherError::WatchLimit { path, watched, requested, limit } => {
+                write!(f, "Inotify watch limit reached while watching '{}' ({} of {} watches added",
+                       path.display(), watched, requested)?;
+
+                match limit {
+                    Some(limit) => write!(f, ", max_user_watches is {}; raise it with \
+                                              `sysctl fs.inotify.max_user_watches=<n>`)", limit),
+                    None => write!(f, ")"),
+                }
+            },
+            WatcherError::PathNotFound(path) => write!(f, "Path not found: '{}'", path.display()),
+            WatcherError::Walk(e) => write!(f, "Failed to traverse directory: {}", e),
+            WatcherError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WatcherError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WatcherError::Init(e) | WatcherError::Io(e) => Some(e),
+            WatcherError::Walk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WatcherError {
+    fn from(e: io::Error) -> WatcherError { WatcherError::Io(e) }
+}
+
+impl From<walkdir::Error> for WatcherError {
+    fn from(e: walkdir::Error) -> WatcherError {
+        let missing_root = e.depth() == 0 &&
+                           e.io_error().map(|e| e.kind() == io::ErrorKind::NotFound).unwrap_or(false);
+
+        match (missing_root, e.path()) {
+            (true, Some(path)) => WatcherError::PathNotFound(path.to_path_buf()),
+            _ => WatcherError::Walk(e),
+        }
+    }
+}
+
+// Adds a watch, translating the errno values worth distinguishing.
+pub(crate) fn add_watch(inotify: &mut Inotify, path: &Path, mask: WatchMask)
+    -> Result<WatchDescriptor, WatcherError> {
+    inotify.add_watch(path, mask).map_err(|e| {
+        let path = path.to_path_buf();
+
+        match e.raw_os_error() {
+            Some(libc::ENOSPC) => WatcherError::WatchLimit {
+                path,
+                watched: 0,
+                requested: 1,
+                limit: watchers::max_user_watches(),
+            },
+            Some(libc::ENOENT) => WatcherError::PathNotFound(path),
+            _ => WatcherError::Io(e),
+        }
+    })
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EventKind {
+    Created,
+    Modified,
+    Deleted,
+    /// The kernel's event queue overflowed and an unknown number of events
+    /// were lost. The path is the watched root; anything below it may have
+    /// changed.
+    Rescan,
+    /// A burst of changes was collapsed into this event; see `Storm`.
+    Storm,
+    /// Emitted on a schedule rather than for a change, see `Scheduler`. The
+    /// path is the watched root.
+    Timer,
+    /// A supervised watcher recovered from an error and watches the tree
+    /// again, see `WatcherBuilder::supervise`. The path is the watched root;
+    /// changes made meanwhile may have been missed.
+    Recovered,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Modified => "modified",
+            EventKind::Deleted => "deleted",
+            EventKind::Rescan => "rescan",
+            EventKind::Storm => "storm",
+            EventKind::Timer => "timer",
+            EventKind::Recovered => "recovered",
+        }
+    }
+}
+
+/// Whether a change was made by this process, as marked with the watcher's
+/// `WriteGuard`, or by anything else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Origin {
+    #[default]
+    External,
+    Own,
+}
+
+/// A change reported by a `Watcher`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchEvent {
+    pub kind: EventKind,
+    /// The changed path, i.e. the watched directory joined with the name
+    /// reported by inotify. Absolute if the watched root is absolute.
+    #[cfg_attr(feature = "serde", serde(with = "serde_path"))]
+    pub path: PathBuf,
+    /// The root the watcher was created for. For file watchers, this is the
+    /// directory containing the file.
+    #[cfg_attr(feature = "serde", serde(with = "serde_path"))]
+    pub root: PathBuf,
+    pub is_dir: bool,
+    /// Only populated if the watcher was built with `with_metadata(true)`,
+    /// and never for deleted paths.
+    pub metadata: Option<Metadata>,
+    /// Only populated for `EventKind::Storm` events.
+    pub storm: Option<Storm>,
+    /// Absent from events sent by older versions, which had no `WriteGuard`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub origin: Origin,
+    /// The name of the schedule, only populated for `EventKind::Timer`
+    /// events.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timer: Option<String>,
+}
+
+/// Summarizes the changes collapsed into an `EventKind::Storm` event, whose
+/// path is the watched root.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Storm {
+    /// The number of collapsed events.
+    pub count: usize,
+    /// The affected directories directly inside the root, sorted. Contains
+    /// the root itself if files directly inside it changed.
+    #[cfg_attr(feature = "serde", serde(with = "serde_path::many"))]
+    pub dirs: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// The state of the changed path at the time the event was read.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub file_type: FileType,
+}
+
+impl Metadata {
+    /// Reads the metadata of `path` without following symlinks.
+    pub fn read(path: &Path) -> Option<Metadata> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        let file_type = metadata.file_type();
+
+        let file_type = if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_file() {
+            FileType::File
+        } else {
+            FileType::Other
+        };
+
+        Some(Metadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            file_type,
+        })
+    }
+}
+
+impl WatchEvent {
+    /// The changed path relative to the watched root.
+    pub fn relative_path(&self) -> &Path {
+        self.path.strip_prefix(&self.root).unwrap_or(&self.path)
+    }
+}
+
+// Paths are serialized as strings, or as arrays of bytes if they are not
+// valid UTF-8, which serde cannot represent as strings.
+#[cfg(feature = "serde")]
+mod serde_path {
+    use std::ffi::OsString;
+    use std::fmt;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Serialize};
+
+    struct Field<'a>(&'a Path);
+
+    impl Serialize for Field<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0.to_str() {
+                Some(path) => serializer.serialize_str(path),
+                None => serializer.collect_seq(self.0.as_os_str().as_bytes()),
+            }
+        }
+    }
+
+    struct Owned(PathBuf);
+
+    impl<'de> Deserialize<'de> for Owned {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Owned, D::Error> {
+            deserializer.deserialize_any(PathVisitor).map(Owned)
+        }
+    }
+
+    struct PathVisitor;
+
+    impl<'de> Visitor<'de> for PathVisitor {
+        type Value = PathBuf;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a path as a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, path: &str) -> Result<PathBuf, E> { Ok(PathBuf::from(path)) }
+
+        fn visit_bytes<E: de::Error>(self, path: &[u8]) -> Result<PathBuf, E> {
+            Ok(PathBuf::from(OsString::from_vec(path.to_vec())))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+            let mut bytes = Vec::new();
+
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+
+            Ok(PathBuf::from(OsString::from_vec(bytes)))
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        Field(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Owned::deserialize(deserializer).map(|owned| owned.0)
+    }
+
+    pub mod many {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(paths.iter().map(|path| Field(path)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+            let paths: Vec<Owned> = Vec::deserialize(deserializer)?;
+
+            Ok(paths.into_iter().map(|owned| owned.0).collect())
+        }
+    }
+}
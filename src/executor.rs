@@ -1,30 +1,190 @@
-use std::io::Error;
-use std::process::Command;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, Read, Write};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+type OutputHandler = Arc<dyn Fn(ExitStatus, &[u8]) + Send + Sync>;
+
+// Left of ARG_MAX, for what the kernel counts beyond the arguments and the
+// environment.
+const ARG_HEADROOM: usize = 4096;
+
+/// The bytes an argument or environment entry takes from `ARG_MAX`.
+pub(crate) fn arg_size(arg: &OsStr) -> usize { arg.len() + 1 + mem::size_of::<*const libc::c_char>() }
+
+#[derive(Clone)]
 pub struct Executor {
     executable: String,
     arguments: Vec<String>,
+    show_stdout: bool,
+    on_output: Option<OutputHandler>,
 }
 
 impl Executor {
-    pub fn new(command_and_args: &Vec<String>) -> Executor {
+    pub fn new(command_and_args: &[String]) -> Executor {
         let (exec, args) = command_and_args.split_first().unwrap();
 
         Executor {
             executable: exec.to_string(),
             arguments: args.to_vec(),
+            show_stdout: false,
+            on_output: None,
         }
     }
 
+    /// Passes the command's stdout through instead of discarding it.
+    pub fn show_stdout(mut self, show: bool) -> Executor {
+        self.show_stdout = show;
+        self
+    }
+
+    /// Passes the command's stderr to `handler` once it exits, instead of
+    /// printing it if the command failed.
+    pub fn on_output<F>(mut self, handler: F) -> Executor
+        where F: Fn(ExitStatus, &[u8]) + Send + Sync + 'static {
+        self.on_output = Some(Arc::new(handler));
+        self
+    }
+
     pub fn execute(&self) -> Result<(), Error> {
-        let output = Command::new(&self.executable)
+        self.spawn()?.wait()?;
+
+        Ok(())
+    }
+
+    /// Starts the command without waiting for it to finish. It runs in its own
+    /// process group, so that terminating it also ends the processes it
+    /// started, e.g. a server started by `sh -c` or `cargo run`. It therefore
+    /// does not receive the terminal's Ctrl-C itself, see `Runner::shutdown`.
+    /// Its stdin is `/dev/null`, as reading the terminal from a background
+    /// process group would stop it.
+    pub fn spawn(&self) -> Result<Execution, Error> { self.spawn_with_env(&[]) }
+
+    /// Starts the command with additional environment variables.
+    pub fn spawn_with_env(&self, env: &[(&str, OsString)]) -> Result<Execution, Error> {
+        self.spawn_with(env, &[] as &[&OsStr], None)
+    }
+
+    /// The bytes left for the further arguments of `spawn_with` with `env`,
+    /// before the system's limit on the size of a command line is hit.
+    pub(crate) fn args_budget(&self, env: &[(&str, OsString)]) -> usize {
+        let limit = match unsafe { libc::sysconf(libc::_SC_ARG_MAX) } {
+            limit if limit > 0 => limit as usize,
+            _ => 128 * 1024,
+        };
+
+        let own = std::iter::once(&self.executable).chain(&self.arguments).map(|arg| arg_size(arg.as_ref()));
+        let inherited = env::vars_os().map(|(key, value)| arg_size(&key) + value.len() + 1);
+        let added = env.iter().map(|(key, value)| arg_size(key.as_ref()) + value.len() + 1);
+
+        limit.saturating_sub(own.chain(inherited).chain(added).sum::<usize>() + ARG_HEADROOM)
+    }
+
+    /// Starts the command with additional environment variables and
+    /// arguments, writing `input` to its stdin if given.
+    pub fn spawn_with<A: AsRef<OsStr>>(&self, env: &[(&str, OsString)], args: &[A], input: Option<Vec<u8>>)
+                                       -> Result<Execution, Error> {
+        let mut child = Command::new(&self.executable)
                         .args(&self.arguments)
-                        .output()?;
+                        .args(args)
+                        .envs(env.iter().map(|(key, value)| (key, value)))
+                        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+                        .stdout(if self.show_stdout { Stdio::inherit() } else { Stdio::null() })
+                        .stderr(Stdio::piped())
+                        .process_group(0)
+                        .spawn()?;
 
-        if !output.status.success() {
-            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        // Written from another thread, so a long input cannot block until the command reads it.
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            thread::spawn(move || stdin.write_all(&input));
         }
 
-        Ok(())
+        // Read stderr concurrently, so a chatty command cannot fill the pipe and block.
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                let _ = stderr.read_to_end(&mut output);
+
+                output
+            })
+        });
+
+        Ok(Execution {
+            child,
+            stderr,
+            on_output: self.on_output.clone(),
+        })
+    }
+}
+
+/// A running command started by `Executor::spawn`. Its stderr is printed
+/// once it exits unsuccessfully, unless the executor has an output handler.
+pub struct Execution {
+    child: Child,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+    on_output: Option<OutputHandler>,
+}
+
+impl Execution {
+    pub fn id(&self) -> u32 { self.child.id() }
+
+    pub fn wait(mut self) -> Result<ExitStatus, Error> {
+        let status = self.child.wait()?;
+
+        Ok(self.finish(status))
+    }
+
+    /// Returns the exit status if the command has exited, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        match self.child.try_wait()? {
+            Some(status) => Ok(Some(self.finish(status))),
+            None => Ok(None),
+        }
+    }
+
+    /// Sends SIGTERM to the command's process group and waits up to `grace`
+    /// for the command to exit, then kills the group, including processes
+    /// outliving the command.
+    pub fn terminate(mut self, grace: Duration) -> Result<ExitStatus, Error> {
+        let group = -(self.child.id() as libc::pid_t);
+
+        unsafe { libc::kill(group, libc::SIGTERM) };
+
+        let deadline = Instant::now() + grace;
+        let mut status = None;
+
+        while status.is_none() && Instant::now() < deadline {
+            status = self.child.try_wait()?;
+
+            if status.is_none() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        unsafe { libc::kill(group, libc::SIGKILL) };
+
+        match status {
+            Some(status) => Ok(status),
+            None => self.child.wait(),
+        }
+    }
+
+    fn finish(&mut self, status: ExitStatus) -> ExitStatus {
+        if let Some(stderr) = self.stderr.take() {
+            let output = stderr.join().unwrap_or_default();
+
+            match &self.on_output {
+                Some(handler) => handler(status, &output),
+                None if !status.success() => eprintln!("{}", String::from_utf8_lossy(&output)),
+                None => {},
+            }
+        }
+
+        status
     }
 }
@@ -0,0 +1,139 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::ops::Not;
+use std::sync::Arc;
+
+use crate::events::{EventKind, WatchEvent};
+use crate::glob::Glob;
+
+/// A predicate on events, composed from the constructors below, e.g.
+///
+/// `Filter::ext("rs").or(Filter::path_contains("templates")).and(!Filter::kind(EventKind::Deleted))`
+///
+/// A watcher built with a filter drops the events it does not match before
+/// they are returned. `Rescan`, `Recovered` and `Storm` events are never
+/// dropped.
+#[derive(Clone)]
+pub struct Filter(Predicate);
+
+#[derive(Clone)]
+enum Predicate {
+    Ext(String),
+    PathContains(String),
+    Glob(Glob),
+    Kind(EventKind),
+    Dir,
+    Custom(Arc<dyn Fn(&WatchEvent) -> bool + Send + Sync>),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+impl Filter {
+    /// Matches paths with this extension, given with or without the dot.
+    pub fn ext(ext: &str) -> Filter { Filter(Predicate::Ext(String::from(ext.trim_start_matches('.')))) }
+
+    /// Matches paths containing `part`, e.g. a directory name.
+    pub fn path_contains(part: &str) -> Filter { Filter(Predicate::PathContains(String::from(part))) }
+
+    /// Matches paths relative to the watched root against a pattern like
+    /// `src/**/*.rs`, see `Glob`.
+    pub fn glob(pattern: &str) -> Filter { Filter(Predicate::Glob(Glob::new(pattern))) }
+
+    pub fn kind(kind: EventKind) -> Filter { Filter(Predicate::Kind(kind)) }
+
+    /// Matches events for directories.
+    pub fn dir() -> Filter { Filter(Predicate::Dir) }
+
+    pub fn custom<F>(predicate: F) -> Filter
+        where F: Fn(&WatchEvent) -> bool + Send + Sync + 'static {
+        Filter(Predicate::Custom(Arc::new(predicate)))
+    }
+
+    // Reads like the other constructors; `!filter` works as well.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(filter: Filter) -> Filter { Filter(Predicate::Not(Box::new(filter.0))) }
+
+    pub fn and(self, other: Filter) -> Filter { Filter(Predicate::And(Box::new(self.0), Box::new(other.0))) }
+
+    pub fn or(self, other: Filter) -> Filter { Filter(Predicate::Or(Box::new(self.0), Box::new(other.0))) }
+
+    pub fn matches(&self, event: &WatchEvent) -> bool {
+        match event.kind {
+            EventKind::Rescan | EventKind::Recovered | EventKind::Storm => true,
+            _ => self.0.matches(event),
+        }
+    }
+}
+
+impl Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter { Filter::not(self) }
+}
+
+impl Predicate {
+    fn matches(&self, event: &WatchEvent) -> bool {
+        match self {
+            Predicate::Ext(ext) => event.path.extension() == Some(OsStr::new(ext)),
+            Predicate::PathContains(part) => event.path.to_string_lossy().contains(part.as_str()),
+            Predicate::Glob(glob) => glob.matches(event.relative_path()),
+            Predicate::Kind(kind) => event.kind == *kind,
+            Predicate::Dir => event.is_dir,
+            Predicate::Custom(predicate) => predicate(event),
+            Predicate::Not(predicate) => !predicate.matches(event),
+            Predicate::And(a, b) => a.matches(event) && b.matches(event),
+            Predicate::Or(a, b) => a.matches(event) || b.matches(event),
+        }
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.0.fmt(f) }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Predicate::Ext(ext) => write!(f, "ext({:?})", ext),
+            Predicate::PathContains(part) => write!(f, "path_contains({:?})", part),
+            Predicate::Glob(glob) => write!(f, "glob({:?})", glob.as_str()),
+            Predicate::Kind(kind) => write!(f, "kind({:?})", kind),
+            Predicate::Dir => write!(f, "dir()"),
+            Predicate::Custom(_) => write!(f, "custom(..)"),
+            Predicate::Not(predicate) => write!(f, "not({:?})", predicate),
+            Predicate::And(a, b) => write!(f, "({:?} and {:?})", a, b),
+            Predicate::Or(a, b) => write!(f, "({:?} or {:?})", a, b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use crate::source::EventSource;
+
+    fn events(mock: &mut MockWatcher) -> Vec<WatchEvent> { mock.next_batch().unwrap() }
+
+    #[test]
+    fn combinators() {
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("src/main.rs").modify("README.md").create_dir("src/bin").delete("target/out.rs");
+
+        let filter = Filter::ext("rs").and(!Filter::path_contains("target")).or(Filter::dir());
+        let matched: Vec<bool> = events(&mut mock).iter().map(|event| filter.matches(event)).collect();
+
+        assert_eq!(matched, [true, false, true, false]);
+    }
+
+    #[test]
+    fn rescans_and_storms_always_match() {
+        let mut mock = MockWatcher::new("/project");
+        mock.rescan().storm(100, &["src"]);
+
+        let filter = Filter::kind(EventKind::Created);
+
+        assert!(events(&mut mock).iter().all(|event| filter.matches(event)));
+    }
+}
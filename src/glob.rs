@@ -0,0 +1,84 @@
+use std::path::Path;
+
+/// A shell-style pattern matched against relative paths, e.g. `src/**/*.scss`.
+///
+/// `*` matches any part of a name and `?` any single character, `**` matches
+/// any number of directories, and `{a,b}` either alternative.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+    // One list of components per alternative, once braces are expanded.
+    alternatives: Vec<Vec<String>>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let alternatives = expand_braces(pattern)
+            .iter()
+            .map(|pattern| pattern.split('/').filter(|c| !c.is_empty()).map(String::from).collect())
+            .collect();
+
+        Glob {
+            pattern: String::from(pattern),
+            alternatives,
+        }
+    }
+
+    pub fn as_str(&self) -> &str { &self.pattern }
+
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        let names: Vec<String> = path.as_ref()
+                                     .iter()
+                                     .map(|name| name.to_string_lossy().into_owned())
+                                     .collect();
+
+        self.alternatives.iter().any(|components| matches_components(components, &names))
+    }
+}
+
+fn matches_components(pattern: &[String], names: &[String]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=names.len()).any(|skip| matches_components(rest, &names[skip..]))
+        },
+        Some((first, rest)) => match names.split_first() {
+            Some((name, names)) => {
+                let pattern: Vec<char> = first.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+
+                matches_name(&pattern, &name) && matches_components(rest, names)
+            },
+            None => false,
+        },
+    }
+}
+
+fn matches_name(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some(('*', rest)), _) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        (Some(('?', rest)), Some((_, name))) => matches_name(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => matches_name(rest, name),
+        _ => false,
+    }
+}
+
+// Expands the first `{a,b}` group and recurses into the results. Unbalanced
+// braces are matched literally.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let open = match pattern.find('{') {
+        Some(open) => open,
+        None => return vec![String::from(pattern)],
+    };
+
+    let close = match pattern[open..].find('}') {
+        Some(close) => open + close,
+        None => return vec![String::from(pattern)],
+    };
+
+    pattern[open + 1..close]
+        .split(',')
+        .flat_map(|alternative| expand_braces(&format!("{}{}{}", &pattern[..open], alternative, &pattern[close + 1..])))
+        .collect()
+}
@@ -0,0 +1,188 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::events::{Origin, WatchEvent};
+
+/// How long a path stays marked after its `Marked` is dropped, since the
+/// watcher may only read the events of the write later.
+pub const LINGER: Duration = Duration::from_millis(500);
+
+/// What a `Watcher` does with the events for paths marked with its
+/// `WriteGuard`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OwnWrites {
+    /// Report them with `Origin::Own`.
+    Tag,
+    /// Drop them.
+    Suppress,
+}
+
+struct Mark {
+    id: u64,
+    path: PathBuf,
+    // `None` while the `Marked` is alive.
+    until: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Marks {
+    next_id: u64,
+    marks: Vec<Mark>,
+}
+
+/// Marks paths this process is about to write, so a `Watcher` can tell its
+/// own changes apart from external edits, see `Watcher::write_guard`. Clones
+/// share the marks, so the guard can be passed to the code doing the
+/// writing.
+///
+/// A marked directory covers everything below it. Anything else writing to
+/// a marked path meanwhile is taken for this process as well.
+#[derive(Clone, Default)]
+pub struct WriteGuard {
+    marks: Arc<Mutex<Marks>>,
+}
+
+impl WriteGuard {
+    pub fn new() -> WriteGuard { WriteGuard::default() }
+
+    /// Marks `path` until the returned `Marked` is dropped, and for `LINGER`
+    /// after.
+    pub fn mark<P: AsRef<Path>>(&self, path: P) -> Marked {
+        Marked {
+            guard: self.clone(),
+            id: self.push(path.as_ref(), None),
+        }
+    }
+
+    /// Marks `path` for `duration` from now.
+    pub fn mark_for<P: AsRef<Path>>(&self, path: P, duration: Duration) {
+        self.push(path.as_ref(), Some(Instant::now() + duration));
+    }
+
+    /// Runs `write` with `path` marked, e.g. `guard.write(&path, || fs::write(&path, data))`.
+    pub fn write<P: AsRef<Path>, T, F: FnOnce() -> T>(&self, path: P, write: F) -> T {
+        let _marked = self.mark(path);
+
+        write()
+    }
+
+    pub fn is_marked<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = absolute(path.as_ref());
+        let now = Instant::now();
+
+        self.marks.lock().unwrap().marks.iter().any(|mark| mark.covers(&path, now))
+    }
+
+    fn push(&self, path: &Path, until: Option<Instant>) -> u64 {
+        let mut marks = self.marks.lock().unwrap();
+        let id = marks.next_id;
+
+        marks.next_id += 1;
+        marks.marks.push(Mark {
+            id,
+            path: absolute(path),
+            until,
+        });
+
+        id
+    }
+
+    // Tags or drops the events for marked paths, forgetting expired marks.
+    pub(crate) fn apply(&self, batch: &mut Vec<WatchEvent>, policy: OwnWrites) {
+        let mut marks = self.marks.lock().unwrap();
+        let now = Instant::now();
+
+        marks.marks.retain(|mark| mark.until.map_or(true, |until| until > now));
+
+        if marks.marks.is_empty() {
+            return;
+        }
+
+        for event in batch.iter_mut() {
+            let path = absolute(&event.path);
+
+            if marks.marks.iter().any(|mark| mark.covers(&path, now)) {
+                event.origin = Origin::Own;
+            }
+        }
+
+        if policy == OwnWrites::Suppress {
+            batch.retain(|event| event.origin != Origin::Own);
+        }
+    }
+}
+
+impl Mark {
+    fn covers(&self, path: &Path, now: Instant) -> bool {
+        self.until.map_or(true, |until| until > now) && path.starts_with(&self.path)
+    }
+}
+
+/// Keeps a path marked with a `WriteGuard` while alive.
+#[must_use = "the path is only marked until this is dropped"]
+pub struct Marked {
+    guard: WriteGuard,
+    id: u64,
+}
+
+impl Drop for Marked {
+    fn drop(&mut self) {
+        let mut marks = self.guard.marks.lock().unwrap();
+
+        if let Some(mark) = marks.marks.iter_mut().find(|mark| mark.id == self.id) {
+            mark.until = Some(Instant::now() + LINGER);
+        }
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use std::thread;
+
+    fn event(path: &str) -> WatchEvent {
+        WatchEvent {
+            kind: EventKind::Modified,
+            path: PathBuf::from(path),
+            root: PathBuf::from("/project"),
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    #[test]
+    fn marks_cover_paths_below_them_until_they_expire() {
+        let guard = WriteGuard::new();
+        let marked = guard.mark("/project/build");
+        guard.mark_for("/project/out.log", Duration::from_millis(50));
+
+        assert!(guard.is_marked("/project/build/app.js"));
+        assert!(guard.is_marked("/project/out.log"));
+        assert!(!guard.is_marked("/project/src/main.rs"));
+
+        let mut batch = vec![event("/project/build/app.js"), event("/project/src/main.rs")];
+        guard.apply(&mut batch, OwnWrites::Tag);
+        assert_eq!(batch.iter().map(|e| e.origin).collect::<Vec<_>>(), [Origin::Own, Origin::External]);
+
+        let mut batch = vec![event("/project/build/app.js"), event("/project/src/main.rs")];
+        guard.apply(&mut batch, OwnWrites::Suppress);
+        assert_eq!(batch, [event("/project/src/main.rs")]);
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!guard.is_marked("/project/out.log"));
+
+        // Still marked for a while after the write, for its late events.
+        drop(marked);
+        assert!(guard.is_marked("/project/build/app.js"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::events::{EventKind, Origin, Storm, WatchEvent};
+use crate::snapshot::{escape_path, unescape_path};
+
+// The first line of every journal, to refuse files in another format.
+const HEADER: &str = "aa-journal 1";
+
+const KINDS: &[EventKind] = &[
+    EventKind::Created,
+    EventKind::Modified,
+    EventKind::Deleted,
+    EventKind::Rescan,
+    EventKind::Storm,
+    EventKind::Timer,
+    EventKind::Recovered,
+];
+
+/// An event as recorded in a `Journal`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Numbers the events in the order they were reported, starting at 1.
+    pub seq: u64,
+    /// The time since the journal was started, from a monotonic clock.
+    pub elapsed: Duration,
+    pub event: WatchEvent,
+}
+
+/// Records every event a watcher reports, to reproduce the sequence later
+/// with `Dispatcher::replay`. See `WatcherBuilder::journal`.
+///
+/// Saved as text, one event per line, with paths and timer names escaped
+/// like in a `Snapshot`. Metadata and the directories of storms are not
+/// recorded.
+pub struct Journal {
+    writer: BufWriter<File>,
+    started: Instant,
+    seq: u64,
+}
+
+impl Journal {
+    /// Starts a journal at `path`, replacing an existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Journal> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writeln!(writer, "{}", HEADER)?;
+        writer.flush()?;
+
+        Ok(Journal {
+            writer,
+            started: Instant::now(),
+            seq: 0,
+        })
+    }
+
+    /// Appends the event. Every line is flushed, so the journal is complete
+    /// up to a crash.
+    pub fn record(&mut self, event: &WatchEvent) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+        let count = event.storm.as_ref().map(|storm| storm.count.to_string()).unwrap_or_else(|| String::from("-"));
+        let timer = event.timer.as_deref().map(|name| escape_path(Path::new(name))).unwrap_or_else(|| String::from("-"));
+
+        self.seq += 1;
+
+        writeln!(self.writer, "{}\t{}.{:09}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", self.seq, elapsed.as_secs(),
+                 elapsed.subsec_nanos(), event.kind.name(), if event.is_dir { "d" } else { "f" }, count,
+                 if event.origin == Origin::Own { "o" } else { "e" }, timer,
+                 escape_path(&event.root), escape_path(&event.path))?;
+        self.writer.flush()
+    }
+
+    /// Reads every entry of the journal at `path`, in order.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<Entry>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a journal file"));
+        }
+
+        lines.map(|line| parse_entry(&line?)).collect()
+    }
+}
+
+fn parse_entry(line: &str) -> io::Result<Entry> {
+    let fields: Vec<&str> = line.splitn(9, '\t').collect();
+
+    let (seq, elapsed, kind, file_type, count, origin, timer, root, path) = match fields[..] {
+        [seq, elapsed, kind, file_type, count, origin, timer, root, path] => {
+            (seq, elapsed, kind, file_type, count, origin, timer, root, path)
+        },
+        _ => return Err(invalid(line)),
+    };
+
+    let (secs, nanos) = elapsed.split_once('.').ok_or_else(|| invalid(elapsed))?;
+    let kind = *KINDS.iter().find(|k| k.name() == kind).ok_or_else(|| invalid(kind))?;
+
+    // Only timer events have a name, which may itself be `-`.
+    let timer = match kind {
+        EventKind::Timer => Some(unescape_path(timer)?.into_os_string().into_string().map_err(|_| invalid(timer))?),
+        _ => None,
+    };
+
+    let storm = match count {
+        "-" => None,
+        count => Some(Storm {
+            count: count.parse().map_err(invalid)?,
+            dirs: Vec::new(),
+        }),
+    };
+
+    Ok(Entry {
+        seq: seq.parse().map_err(invalid)?,
+        elapsed: Duration::new(secs.parse().map_err(invalid)?, nanos.parse().map_err(invalid)?),
+        event: WatchEvent {
+            kind,
+            path: unescape_path(path)?,
+            root: unescape_path(root)?,
+            is_dir: file_type == "d",
+            metadata: None,
+            storm,
+            origin: if origin == "o" { Origin::Own } else { Origin::External },
+            timer,
+        },
+    })
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    fn event(kind: EventKind, path: PathBuf, root: &Path) -> WatchEvent {
+        WatchEvent {
+            kind,
+            path,
+            root: root.to_path_buf(),
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    #[test]
+    fn reads_back_what_was_recorded() {
+        let path = env::temp_dir().join(format!("aa-journal-{}", process::id()));
+        let root = PathBuf::from("/project");
+
+        let mut own = event(EventKind::Modified, root.join(OsStr::from_bytes(b"tab\there \xff")), &root);
+        let mut timer = event(EventKind::Timer, root.clone(), &root);
+        own.origin = Origin::Own;
+        timer.timer = Some(String::from("-"));
+
+        let mut journal = Journal::create(&path).unwrap();
+        journal.record(&own).unwrap();
+        journal.record(&timer).unwrap();
+
+        let events: Vec<WatchEvent> = Journal::read(&path).unwrap().into_iter().map(|entry| entry.event).collect();
+        assert_eq!(events, [own, timer]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// The number of bytes read from the start of a file to detect its kind.
+const SNIFF_SIZE: usize = 8192;
+
+/// What a file contains, detected from its first bytes rather than its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileKind {
+    Text,
+    /// PNG, JPEG, GIF, WebP, BMP, ICO or TIFF.
+    Image,
+    /// Zip, gzip, bzip2, xz, zstd, 7z or tar.
+    Archive,
+    /// ELF, Mach-O and PE executables and objects, static libraries and
+    /// WebAssembly modules.
+    Object,
+    /// PDF documents.
+    Pdf,
+    /// Anything else that is not text.
+    Binary,
+}
+
+impl FileKind {
+    pub fn sniff<P: AsRef<Path>>(path: P) -> io::Result<FileKind> {
+        let mut buffer = Vec::with_capacity(SNIFF_SIZE);
+
+        File::open(path)?.take(SNIFF_SIZE as u64).read_to_end(&mut buffer)?;
+
+        Ok(FileKind::detect(&buffer))
+    }
+
+    /// Detects the kind from the first bytes of a file.
+    pub fn detect(bytes: &[u8]) -> FileKind {
+        const MAGIC: &[(&[u8], FileKind)] = &[
+            (b"\x89PNG\r\n\x1a\n", FileKind::Image),
+            (b"\xff\xd8\xff", FileKind::Image),
+            (b"GIF87a", FileKind::Image),
+            (b"GIF89a", FileKind::Image),
+            (b"\x00\x00\x01\x00", FileKind::Image),
+            (b"II*\x00", FileKind::Image),
+            (b"MM\x00*", FileKind::Image),
+            (b"PK\x03\x04", FileKind::Archive),
+            (b"PK\x05\x06", FileKind::Archive),
+            (b"\x1f\x8b", FileKind::Archive),
+            (b"\xfd7zXZ\x00", FileKind::Archive),
+            (b"\x28\xb5\x2f\xfd", FileKind::Archive),
+            (b"7z\xbc\xaf\x27\x1c", FileKind::Archive),
+            (b"\x7fELF", FileKind::Object),
+            (b"\xfe\xed\xfa\xce", FileKind::Object),
+            (b"\xfe\xed\xfa\xcf", FileKind::Object),
+            (b"\xce\xfa\xed\xfe", FileKind::Object),
+            (b"\xcf\xfa\xed\xfe", FileKind::Object),
+            (b"\xca\xfe\xba\xbe", FileKind::Object),
+            (b"!<arch>\n", FileKind::Object),
+            (b"\x00asm", FileKind::Object),
+            (b"%PDF-", FileKind::Pdf),
+        ];
+
+        if let Some((_, kind)) = MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+            return *kind;
+        }
+
+        // Two-byte magic numbers also start plenty of text files.
+        const WEAK_MAGIC: &[(&[u8], FileKind)] = &[
+            (b"BM", FileKind::Image),
+            (b"MZ", FileKind::Object),
+        ];
+
+        if let Some((_, kind)) = WEAK_MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic) && !is_text(bytes)) {
+            return *kind;
+        }
+
+        if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            return FileKind::Image;
+        }
+
+        if bytes.len() >= 10 && bytes.starts_with(b"BZh") && &bytes[4..10] == b"\x31\x41\x59\x26\x53\x59" {
+            return FileKind::Archive;
+        }
+
+        if bytes.len() >= 262 && &bytes[257..262] == b"ustar" {
+            return FileKind::Archive;
+        }
+
+        if is_text(bytes) { FileKind::Text } else { FileKind::Binary }
+    }
+
+    pub fn is_binary(&self) -> bool { *self != FileKind::Text }
+}
+
+// Text is valid UTF-8, except for a character cut off at the end, or mostly
+// printable in some other encoding. It never contains NUL bytes.
+fn is_text(bytes: &[u8]) -> bool {
+    if bytes.contains(&0) {
+        return false;
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) if e.error_len().is_none() => true,
+        Err(_) => {
+            let control = bytes.iter()
+                               .filter(|&&b| b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b))
+                               .count();
+
+            control * 10 <= bytes.len()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_kinds_from_magic_numbers_and_content() {
+        assert_eq!(FileKind::detect(b"\x89PNG\r\n\x1a\n...."), FileKind::Image);
+        assert_eq!(FileKind::detect(b"\x7fELF\x02\x01\x01"), FileKind::Object);
+        assert_eq!(FileKind::detect(b"%PDF-1.7"), FileKind::Pdf);
+        assert_eq!(FileKind::detect(b"fn main() {}\n"), FileKind::Text);
+        assert_eq!(FileKind::detect(b"\x00\x01\x02\x03"), FileKind::Binary);
+
+        // Weak magic numbers only count for content that is not text.
+        assert_eq!(FileKind::detect(b"MZ\x90\x00\x03\x00"), FileKind::Object);
+        assert_eq!(FileKind::detect(b"MZ is a text file"), FileKind::Text);
+
+        // A UTF-8 character cut off by the sniff size is still text.
+        assert_eq!(FileKind::detect("café".as_bytes().split_last().unwrap().1), FileKind::Text);
+    }
+}
@@ -1,13 +1,58 @@
+#[cfg(feature = "slog")]
 #[macro_use]
 extern crate slog;
+#[cfg(feature = "slog")]
 extern crate slog_async;
+#[cfg(feature = "slog")]
 extern crate slog_term;
+extern crate libc;
 
+#[cfg(feature = "slog")]
 use slog::Drain;
 
+#[cfg(feature = "notify-compat")]
+pub mod compat;
+pub mod cargo;
+pub mod config;
+pub mod content;
+pub mod desktop;
+pub mod dispatcher;
+pub mod docker;
+pub mod error;
+pub mod events;
 pub mod executor;
+pub mod filter;
+pub mod glob;
+pub mod guard;
+pub mod journal;
+pub mod kind;
+pub mod log;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mock;
+pub mod pause;
+pub mod pipeline;
+pub mod reloader;
+#[cfg(feature = "serde")]
+pub mod remote;
+pub mod runner;
+pub mod scheduler;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod set;
+#[cfg(feature = "serde")]
+pub mod sink;
+pub mod snapshot;
+pub mod source;
+mod stabilize;
+pub mod stats;
+mod storm;
+pub mod systemd;
+#[cfg(feature = "tui")]
+pub mod tui;
 pub mod watchers;
 
+#[cfg(feature = "slog")]
 pub fn create_logger(log_level: slog::Level) -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -0,0 +1,26 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+}
+
+/// Receives the log messages of watchers and runners, so any logging
+/// framework can be plugged in. Implemented for `slog::Logger` if the `slog`
+/// feature is enabled, which it is by default.
+pub trait WatcherLog: Send + Sync {
+    fn log(&self, level: Level, message: fmt::Arguments);
+}
+
+#[cfg(feature = "slog")]
+impl WatcherLog for slog::Logger {
+    fn log(&self, level: Level, message: fmt::Arguments) {
+        match level {
+            Level::Error => error!(self, "{}", message),
+            Level::Warning => warn!(self, "{}", message),
+            Level::Info => info!(self, "{}", message),
+        }
+    }
+}
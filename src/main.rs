@@ -7,51 +7,262 @@ extern crate slog;
 
 extern crate aa;
 
+use aa::cargo::{Diagnostic, Project, Severity};
+use aa::config::{Config, ConfigDiff};
+use aa::content;
+use aa::desktop;
 use aa::create_logger;
+use aa::docker::Container;
+use aa::error::WatcherError;
+use aa::events::{EventKind, WatchEvent};
 use aa::executor::Executor;
-use aa::watchers::{Traversal, Watcher};
+use aa::glob::Glob;
+use aa::kind::FileKind;
+#[cfg(feature = "metrics")]
+use aa::metrics::Metrics;
+use aa::reloader::{self, Reloader, Target};
+use aa::runner::{Backpressure, BatchPaths, BatchTrigger, EnvMode, ExecutionMode, ExitPolicy, GRACE_PERIOD, Pipeline, QueuePolicy, Runner, Throttle};
+#[cfg(feature = "tui")]
+use aa::runner::Status;
+use aa::scheduler::Scheduler;
+#[cfg(feature = "script")]
+use aa::script::Script;
+use aa::source::EventSource;
+use aa::systemd;
+use aa::watchers::{HiddenPolicy, LimitPolicy, Plan, SymlinkPolicy, Traversal, Watcher, DEFAULT_HEURISTIC_DIRS};
 
-use std::{env, process};
+#[cfg(feature = "serde")]
+use aa::remote::{self, Address, Receiver};
+#[cfg(feature = "serde")]
+use aa::sink::{JsonSink, Listener};
+#[cfg(feature = "tui")]
+use aa::tui::{Input, Output, Tui};
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+#[cfg(feature = "serde")]
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{env, iter, process};
+
+// Set on SIGINT, so the watcher can save its state before exiting.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// Set once the event loop runs; before that, SIGINT exits directly.
+static LOOPING: AtomicBool = AtomicBool::new(false);
+
+// How often the event loop checks for STOP.
+const STOP_INTERVAL: Duration = Duration::from_millis(200);
+
+// The default for --storm-window, in milliseconds.
+const DEFAULT_STORM_WINDOW: u64 = 1000;
+
+enum Action {
+    Execute(Runner),
+    Reload(Reloader),
+    // With --script, which runs the command as the action `run`.
+    #[cfg(feature = "script")]
+    Script(Box<Script>),
+}
+
+// Where events come from: a local watcher, or a remote one with --receive.
+enum Source {
+    Watcher(Box<Watcher>),
+    #[cfg(feature = "serde")]
+    Receiver(Receiver),
+}
+
+impl EventSource for Source {
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError> {
+        match self {
+            Source::Watcher(watcher) => watcher.next_event(),
+            #[cfg(feature = "serde")]
+            Source::Receiver(receiver) => receiver.next_event(),
+        }
+    }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        match self {
+            Source::Watcher(watcher) => watcher.next_event_timeout(timeout),
+            #[cfg(feature = "serde")]
+            Source::Receiver(receiver) => receiver.next_event_timeout(timeout),
+        }
+    }
+}
+
+impl Source {
+    fn root(&self) -> &Path {
+        match self {
+            Source::Watcher(watcher) => watcher.root(),
+            #[cfg(feature = "serde")]
+            Source::Receiver(receiver) => receiver.root(),
+        }
+    }
+
+    fn watcher(&mut self) -> Option<&mut Watcher> {
+        match self {
+            Source::Watcher(watcher) => Some(watcher),
+            #[cfg(feature = "serde")]
+            Source::Receiver(_) => None,
+        }
+    }
+
+    fn save_state(&self) -> Result<(), WatcherError> {
+        match self {
+            Source::Watcher(watcher) => watcher.save_state(),
+            #[cfg(feature = "serde")]
+            Source::Receiver(_) => Ok(()),
+        }
+    }
+}
 
 fn main() {
     ctrlc::set_handler(move || {
-        process::exit(0);
+        if !LOOPING.load(Ordering::SeqCst) {
+            process::exit(130);
+        }
+        STOP.store(true, Ordering::SeqCst);
     }).expect("Error setting SIGINT handler");
 
     let matches = clap_app!(aa =>
         (version: "0.3.0")
         (author: "Richard M. <scripts.richard@gmail.com>")
         (about: "A'a - a hot reloader to watch a directory or single file and execute a command when it is modified.")
-        (@arg COMMAND: +required +multiple "The command to be executed")
+        (@arg COMMAND: +multiple required_unless[PID PIDFILE json dry_run FORWARD CARGO CONFIG DOCKER_EXEC SCRIPT] "The command to be executed")
+        (@arg json: --json "Print events to stdout as newline-delimited JSON")
+        (@arg dry_run: --("dry-run") conflicts_with[FILE] "List the directories that would be watched, and exit")
+        (@arg CARGO: --cargo +takes_value possible_values(&["check", "test", "run"]) conflicts_with[FILE RECEIVE PID PIDFILE] "Watch the Cargo project's sources and run this cargo subcommand, passing COMMAND as its arguments")
+        (@arg FORWARD: --forward +takes_value conflicts_with[json] "Send events to a receiver at FORWARD, a host:port or a Unix socket path")
+        (@arg RECEIVE: --receive +takes_value conflicts_with[FILE dry_run FORWARD] "Listen at RECEIVE for forwarded events and handle them as changes below --path")
+        (@arg RSYNC: --rsync +takes_value requires[RECEIVE] "Copy changed files from this rsync source, e.g. host:/path, before handling them")
+        (@arg tui: --tui conflicts_with[json verbose] "Show a full-screen status display with keys to pause, reload and quit")
+        (@arg systemd: --systemd "Report readiness and watchdog pings to systemd, and serve --json on an activated socket")
         (@arg verbose: -v --verbose +multiple "Prints additional output")
         (@arg recursive: -r --recursive "Recursively watch the directory")
+        (@arg HEURISTIC_DIRS: --("heuristic-dirs") +takes_value "The number of recently modified subdirectories watched without --recursive")
+        (@arg content_check: --("content-check") "Ignore changes that leave a file's content unchanged")
+        (@arg SYMLINKS: --symlinks +takes_value possible_values(&["follow", "no-follow", "top-level"]) "Which symlinked directories are watched (default: follow)")
+        (@arg HIDDEN: --hidden +takes_value possible_values(&["include", "exclude-dirs", "exclude"]) "Which hidden files and directories are watched (default: exclude-dirs)")
+        (@arg initial: --initial "Report every existing file as created on startup")
+        (@arg STATS: --stats +takes_value "Log watcher statistics at most every STATS seconds")
+        (@arg rescan: --rescan "Re-scan the directory tree after the kernel's event queue overflows")
+        (@arg supervise: --supervise "Recover from errors reading changes by watching the tree again, instead of exiting")
+        (@arg STORM: --storm +takes_value "Collapse bursts of more than STORM changes into a single event")
+        (@arg STORM_WINDOW: --("storm-window") +takes_value requires[STORM] "The time in milliseconds a burst must last, and be quiet to end (default: 1000)")
+        (@arg STABILIZE: --stabilize +takes_value "Report a file written to once it is closed or unchanged for STABILIZE milliseconds")
+        (@arg STATE: --state +takes_value "Save the tree's state to this file on exit, and report what changed since on startup")
+        (@arg JOURNAL: --journal +takes_value "Record every event with a timestamp and sequence number to this file")
+        (@arg KIND: --kind +takes_value +multiple number_of_values(1) possible_values(&["text", "image", "archive", "object", "pdf", "binary"]) "Only report files with this kind of content; can be repeated")
+        (@arg MAX_DEPTH: --("max-depth") +takes_value "Watch directories at most MAX_DEPTH levels below the path")
+        (@arg degrade: --degrade "Watch only top-level directories if the inotify watch limit is reached")
         (@arg FILE: -f --file +takes_value "A specific file to be watched")
         (@arg PATH: -p --path +takes_value "A path to be watched")
+        (@arg WATCH_FILE: --("watch-file") +takes_value +multiple number_of_values(1) conflicts_with[FILE RECEIVE] "Also watch this file directly, following it across atomic saves; can be repeated")
+        (@arg DOCKER: --docker +takes_value "Copy changed files into this running container with docker cp before handling them")
+        (@arg DOCKER_PATH: --("docker-path") +takes_value +multiple number_of_values(1) requires[DOCKER] "Copy changes below HOST to PATH in the container, given as HOST:PATH; can be repeated (default: the watched path to the same path)")
+        (@arg DOCKER_EXEC: --("docker-exec") +takes_value requires[DOCKER] conflicts_with[COMMAND PID PIDFILE CARGO] "Run this shell command inside the container after copying, instead of a command on the host")
+        (@arg PID: --pid +takes_value conflicts_with[PIDFILE COMMAND] "Signal this process on change instead of executing a command")
+        (@arg PIDFILE: --pidfile +takes_value conflicts_with[COMMAND] "Signal the process named in this pidfile on change")
+        (@arg restart: --restart conflicts_with[QUEUE JOBS] "Terminate a still running command when a new change arrives")
+        (@arg JOBS: -j --jobs +takes_value "Run the command for up to JOBS changed paths at the same time, with the path in HOTRELOAD_PATH")
+        (@arg ENV: --env +takes_value possible_values(&["event", "batch"]) "Describe the change to the command in HOTRELOAD_* environment variables")
+        (@arg INTERVAL: --interval +takes_value "The minimum time in milliseconds between two executions")
+        (@arg QUEUE: --queue +takes_value possible_values(&["drop", "coalesce", "queue-one"]) "What to do with changes during an execution (default: queue-one)")
+        (@arg BATCH: --batch +takes_value conflicts_with[JOBS QUEUE] "Run the command once no change arrived for BATCH milliseconds, with the distinct changed paths as arguments")
+        (@arg batch_stdin: --("batch-stdin") requires[BATCH] "Write the changed paths of --batch to the command's stdin, one per line, instead")
+        (@arg MAX_QUEUED: --("max-queued") +takes_value "Let at most MAX_QUEUED changes wait for the command, applying --backpressure to more")
+        (@arg BACKPRESSURE: --backpressure +takes_value possible_values(&["block", "drop-oldest", "coalesce"]) requires[MAX_QUEUED] "What to do with changes beyond --max-queued (default: block)")
+        (@arg OUTPUT: --output +takes_value +multiple number_of_values(1) "Ignore changes to paths matching this glob, relative to the path, as written by the command; can be repeated")
+        (@arg SUPPRESS: --suppress +takes_value "Ignore changes to paths the previous run also changed while the command runs and up to SUPPRESS milliseconds after it exits")
+        (@arg notify: --notify "Show a desktop notification when the command fails, with libnotify's notify-send, which has to be installed")
+        (@arg RETRY: --retry +takes_value "Run a failed command again up to RETRY times, unless another change arrives first")
+        (@arg RETRY_DELAY: --("retry-delay") +takes_value requires[RETRY] "The time in milliseconds before the first retry, doubled for each further one (default: 1000)")
+        (@arg THEN: --then +takes_value +multiple number_of_values(1) "Run this shell command next if the command succeeded; can be repeated, with --restart cancelling the remaining steps on change")
+        (@arg TIMEOUT: --timeout +takes_value "Terminate the command, or any step of --then, after TIMEOUT milliseconds and count it as failed")
+        (@arg ON_FAILURE: --("on-failure") +takes_value "Run this shell command when the command failed and is not retried")
+        (@arg CONFIG: --config +takes_value conflicts_with[RECEIVE] "Read the command, its --then steps, files to watch and paths to ignore from this TOML file, and apply changes to it while running")
+        (@arg SCRIPT: --script +takes_value conflicts_with[PID PIDFILE] "Call decide(event) in this rhai script for each change, which names the actions to run: run for the command, or an --action; reloaded when it changes")
+        (@arg ACTION: --action +takes_value +multiple number_of_values(1) requires[SCRIPT] "A shell command the script can run, given as NAME=COMMAND; can be repeated")
+        (@arg METRICS: --metrics +takes_value "Serve /healthz and Prometheus metrics at /metrics over HTTP on this host:port")
+        (@arg EVERY: --every +takes_value +multiple number_of_values(1) "Also run the command every SECONDS, given as [NAME=]SECONDS, naming the timer for --script; can be repeated. While the --tui is paused, timers still fire but do not run the command")
+        (@arg SIGNAL: -s --signal +takes_value conflicts_with[COMMAND] "The signal sent to --pid or --pidfile (default: HUP)")
     ).get_matches();
 
+    if matches.is_present("tui") && cfg!(not(feature = "tui")) {
+        eprintln!("--tui requires aa to be built with the `tui` feature");
+        process::exit(1);
+    }
+
+    if matches.is_present("CONFIG") && cfg!(not(feature = "config")) {
+        eprintln!("--config requires aa to be built with the `config` feature");
+        process::exit(1);
+    }
+
+    if matches.is_present("METRICS") && cfg!(not(feature = "metrics")) {
+        eprintln!("--metrics requires aa to be built with the `metrics` feature");
+        process::exit(1);
+    }
+
+    if matches.is_present("SCRIPT") && cfg!(not(feature = "script")) {
+        eprintln!("--script requires aa to be built with the `script` feature");
+        process::exit(1);
+    }
+
+    // Anything written to the terminal would garble the status display.
     let log_level = match matches.occurrences_of("verbose") {
+        _ if matches.is_present("tui") => slog::Level::Critical,
         0 => slog::Level::Error,
         1 => slog::Level::Info,
         2 => slog::Level::Debug,
-        3 | _ => slog::Level::Trace,
+        _ => slog::Level::Trace,
     };
 
     let logger = create_logger(log_level);
 
-    let mut watcher = if let Some(target) = matches.value_of("FILE") {
-        info!(logger, "Watching file '{}'", target);
+    let project = matches.value_of("CARGO").map(|_| {
+        let dir = matches.value_of_os("PATH").map(PathBuf::from).unwrap_or_else(|| env::current_dir().unwrap());
+
+        Project::find(&dir).unwrap_or_else(|| {
+            eprintln!("No Cargo.toml found in '{}' or its parents", dir.display());
+            process::exit(1);
+        })
+    });
+
+    // Absolute, to recognize the config file's events whatever the root.
+    let config_path = matches.value_of_os("CONFIG").map(|path| env::current_dir().unwrap().join(path));
+    let mut config = match &config_path {
+        Some(path) => load_config(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read '{}': {}", path.display(), e);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
+
+    let mut source = if let Some(address) = matches.value_of("RECEIVE") {
+        let path = matches.value_of_os("PATH").map(PathBuf::from).unwrap_or_else(|| env::current_dir().unwrap());
+
+        info!(logger, "Receiving events at '{}' for '{}'", address, path.display());
 
-        Watcher::file_watcher(&target).unwrap()
+        receiver(address, &path, matches.value_of("RSYNC"), &logger)
+    } else if let Some(target) = matches.value_of_os("FILE") {
+        info!(logger, "Watching file '{}'", Path::new(target).display());
+
+        let mut watcher = Watcher::file_watcher(target).unwrap_or_else(|e| exit_with(&e));
+        watcher.register_logger(logger.new(o!("watcher" => 1)));
+
+        Source::Watcher(Box::new(watcher))
     } else {
-        let path = if let Some(path) = matches.value_of("PATH") {
-            String::from(path)
-        } else if let Some(path) = env::current_dir().unwrap().to_str() {
-            String::from(path)
+        let path = if let Some(project) = &project {
+            project.root().to_path_buf()
+        } else if let Some(path) = matches.value_of_os("PATH") {
+            PathBuf::from(path)
         } else {
-            panic!("Failed to get path");
+            env::current_dir().unwrap()
         };
 
-        info!(logger, "Watching directory '{}'", path);
+        info!(logger, "Watching directory '{}'", path.display());
 
         let traversal = if matches.is_present("recursive") {
             Traversal::RECURSIVE
@@ -59,19 +270,862 @@ fn main() {
             Traversal::HEURISTIC
         };
 
-        Watcher::dir_watcher(&path, traversal).unwrap()
+        let limit_policy = if matches.is_present("degrade") {
+            LimitPolicy::Degrade
+        } else {
+            LimitPolicy::Fail
+        };
+
+        let heuristic_dirs = if matches.is_present("HEURISTIC_DIRS") {
+            value_t!(matches, "HEURISTIC_DIRS", usize).unwrap_or_else(|e| e.exit())
+        } else {
+            DEFAULT_HEURISTIC_DIRS
+        };
+
+        let symlinks = match matches.value_of("SYMLINKS") {
+            Some("no-follow") => SymlinkPolicy::NoFollow,
+            Some("top-level") => SymlinkPolicy::FollowTopLevel,
+            _ => SymlinkPolicy::Follow,
+        };
+
+        let hidden = match matches.value_of("HIDDEN") {
+            Some("include") => HiddenPolicy::IncludeAll,
+            Some("exclude") => HiddenPolicy::ExcludeAll,
+            _ => HiddenPolicy::ExcludeDirs,
+        };
+
+        let builder = match &project {
+            Some(project) => project.watcher(),
+            None => Watcher::builder(&path).traversal(traversal),
+        };
+
+        let mut builder = builder
+            .symlinks(symlinks)
+            .hidden(hidden)
+            .emit_existing(matches.is_present("initial"))
+            .heuristic_dirs(heuristic_dirs)
+            .limit_policy(limit_policy)
+            .rescan_on_overflow(matches.is_present("rescan"))
+            .supervise(matches.is_present("supervise"))
+            .logger(logger.new(o!("watcher" => 1)));
+
+        if matches.is_present("STATS") {
+            let interval = value_t!(matches, "STATS", u64).unwrap_or_else(|e| e.exit());
+            builder = builder.log_stats(Duration::from_secs(interval));
+        }
+
+        if matches.is_present("STORM") {
+            let threshold = value_t!(matches, "STORM", usize).unwrap_or_else(|e| e.exit());
+            let window = if matches.is_present("STORM_WINDOW") {
+                value_t!(matches, "STORM_WINDOW", u64).unwrap_or_else(|e| e.exit())
+            } else {
+                DEFAULT_STORM_WINDOW
+            };
+
+            builder = builder.storm(threshold, Duration::from_millis(window));
+        }
+
+        if matches.is_present("STABILIZE") {
+            let period = value_t!(matches, "STABILIZE", u64).unwrap_or_else(|e| e.exit());
+            builder = builder.stabilize(Duration::from_millis(period));
+        }
+
+        if matches.is_present("MAX_DEPTH") {
+            builder = builder.max_depth(value_t!(matches, "MAX_DEPTH", usize).unwrap_or_else(|e| e.exit()));
+        }
+
+        if let Some(state_file) = matches.value_of_os("STATE") {
+            builder = builder.state_file(state_file);
+        }
+
+        if let Some(journal) = matches.value_of_os("JOURNAL") {
+            builder = builder.journal(journal);
+        }
+
+        for kind in matches.values_of("KIND").into_iter().flatten() {
+            builder = builder.filter_kind(match kind {
+                "text" => FileKind::Text,
+                "image" => FileKind::Image,
+                "archive" => FileKind::Archive,
+                "object" => FileKind::Object,
+                "pdf" => FileKind::Pdf,
+                _ => FileKind::Binary,
+            });
+        }
+
+        if matches.is_present("content_check") {
+            builder = builder.content_check(content::DEFAULT_MAX_SIZE);
+        }
+
+        if matches.is_present("dry_run") {
+            print_plan(&builder.plan().unwrap_or_else(|e| exit_with(&e)));
+            process::exit(0);
+        }
+
+        let mut watcher = builder.build().unwrap_or_else(|e| exit_with(&e));
+
+        for file in matches.values_of_os("WATCH_FILE").into_iter().flatten() {
+            info!(logger, "Watching file '{}'", Path::new(file).display());
+
+            watcher.add_file(file).unwrap_or_else(|e| exit_with(&e));
+        }
+
+        Source::Watcher(Box::new(watcher))
+    };
+
+    if let Some(watcher) = source.watcher() {
+        for file in &config.files {
+            info!(logger, "Watching file '{}'", file.display());
+
+            watcher.add_file(file).unwrap_or_else(|e| exit_with(&e));
+        }
+
+        if let Some(path) = &config_path {
+            watcher.add_file(path).unwrap_or_else(|e| exit_with(&e));
+        }
+    }
+
+    let container = matches.value_of("DOCKER").map(|name| {
+        let mut container = Container::new(name);
+        let mut mapped = false;
+
+        for mapping in matches.values_of("DOCKER_PATH").into_iter().flatten() {
+            let (host, path) = mapping.split_once(':').unwrap_or_else(|| {
+                eprintln!("Invalid --docker-path '{}', expected HOST:PATH", mapping);
+                process::exit(1);
+            });
+
+            container = container.map(host, path);
+            mapped = true;
+        }
+
+        if !mapped {
+            let root = env::current_dir().unwrap().join(source.root());
+            container = container.map(&root, &root);
+        }
+
+        info!(logger, "Copying changes into container '{}'", name);
+
+        container
+    });
+
+    let mut ignored: Vec<Glob> = config.ignore.iter().map(|pattern| Glob::new(pattern)).collect();
+
+    let target = if matches.is_present("PID") {
+        let pid = value_t!(matches, "PID", i32).unwrap_or_else(|e| e.exit());
+
+        if pid <= 0 {
+            eprintln!("Invalid pid '{}', expected a positive number", pid);
+            process::exit(1);
+        }
+
+        info!(logger, "On change, signalling process {}", pid);
+
+        Some(Target::Pid(pid))
+    } else if let Some(file) = matches.value_of("PIDFILE") {
+        info!(logger, "On change, signalling the process in '{}'", file);
+
+        Some(Target::PidFile(String::from(file)))
+    } else {
+        None
+    };
+
+    // The config's command stands in for COMMAND, also as cargo's arguments.
+    let command_for = |config: &Config| {
+        let args = config.command.clone().or_else(|| values_t!(matches.values_of("COMMAND"), String).ok());
+
+        match (&project, matches.value_of("CARGO")) {
+            (Some(project), Some(subcommand)) => Some(project.command(subcommand, &args.unwrap_or_default())),
+            _ => args.or_else(|| {
+                let command = matches.value_of("DOCKER_EXEC")?;
+
+                container.as_ref().map(|container| container.exec_command(command))
+            }),
+        }
+    };
+
+    let command = command_for(&config);
+
+    let summary = match (&command, matches.value_of("PID"), matches.value_of("PIDFILE")) {
+        (_, Some(pid), _) => Some(format!("signal process {}", pid)),
+        (_, _, Some(file)) => Some(format!("signal the process in '{}'", file)),
+        (Some(command), _, _) => Some(command.join(" ")),
+        _ => None,
+    };
+
+    #[cfg(feature = "tui")]
+    let output = Output::default();
+
+    let timeout = if matches.is_present("TIMEOUT") {
+        Some(Duration::from_millis(value_t!(matches, "TIMEOUT", u64).unwrap_or_else(|e| e.exit())))
+    } else {
+        None
+    };
+
+    // Shows the command's output in the status display with --tui.
+    let capture = |executor: Executor| {
+        #[cfg(feature = "tui")]
+        {
+            if matches.is_present("tui") {
+                let output = output.clone();
+                return executor.show_stdout(false).on_output(move |status, bytes| output.record(status, bytes));
+            }
+        }
+
+        executor
+    };
+
+    let step = |pipeline: Pipeline, executor: Executor| match timeout {
+        Some(timeout) => pipeline.step_with_timeout(executor, timeout),
+        None => pipeline.step(executor),
+    };
+
+    let pipeline_for = |command: &[String], config: &Config| {
+        let mut executor = Executor::new(command);
+
+        if project.is_some() {
+            executor = executor.show_stdout(true).on_output(report_cargo);
+        }
+
+        let steps = matches.values_of("THEN").into_iter().flatten().chain(config.then.iter().map(String::as_str)).map(|command| {
+            Executor::new(&shell(command))
+        });
+
+        iter::once(capture(executor)).chain(steps).fold(Pipeline::new(), step)
+    };
+
+    let runner_for = |pipeline: Pipeline| {
+        let mut throttle = Throttle::default();
+
+        if matches.is_present("INTERVAL") {
+            let interval = value_t!(matches, "INTERVAL", u64).unwrap_or_else(|e| e.exit());
+            throttle.min_interval = Duration::from_millis(interval);
+        }
+
+        throttle.queue = match matches.value_of("QUEUE") {
+            Some("drop") => QueuePolicy::Drop,
+            Some("coalesce") => QueuePolicy::Coalesce,
+            _ => QueuePolicy::QueueOne,
+        };
+
+        // `cargo run` usually starts a server that has to be restarted.
+        let mode = if matches.is_present("restart") || matches.value_of("CARGO") == Some("run") {
+            ExecutionMode::RestartOnChange
+        } else if matches.is_present("JOBS") {
+            ExecutionMode::Parallel(value_t!(matches, "JOBS", usize).unwrap_or_else(|e| e.exit()))
+        } else {
+            ExecutionMode::Sequential
+        };
+
+        let env = match matches.value_of("ENV") {
+            Some("event") => EnvMode::Event,
+            Some("batch") => EnvMode::Batch,
+            _ => EnvMode::Off,
+        };
+
+        let mut builder = Runner::builder(pipeline)
+            .throttle(throttle)
+            .mode(mode)
+            .env(env)
+            .logger(logger.new(o!("runner" => 1)));
+
+        for pattern in matches.values_of("OUTPUT").into_iter().flatten() {
+            builder = builder.output(pattern);
+        }
+
+        if matches.is_present("SUPPRESS") {
+            let window = value_t!(matches, "SUPPRESS", u64).unwrap_or_else(|e| e.exit());
+            builder = builder.suppress_own_writes(Duration::from_millis(window));
+        }
+
+        let mut policy = ExitPolicy::default();
+
+        if matches.is_present("RETRY") {
+            policy.retries = value_t!(matches, "RETRY", usize).unwrap_or_else(|e| e.exit());
+        }
+
+        if matches.is_present("RETRY_DELAY") {
+            let delay = value_t!(matches, "RETRY_DELAY", u64).unwrap_or_else(|e| e.exit());
+            policy.backoff = Duration::from_millis(delay);
+        }
+
+        if let Some(command) = matches.value_of("ON_FAILURE") {
+            policy.on_failure = Some(Executor::new(&shell(command)).show_stdout(true));
+        }
+
+        builder = builder.exit_policy(policy);
+
+        if matches.is_present("MAX_QUEUED") {
+            let capacity = value_t!(matches, "MAX_QUEUED", usize).unwrap_or_else(|e| e.exit());
+            let backpressure = match matches.value_of("BACKPRESSURE") {
+                Some("drop-oldest") => Backpressure::DropOldest,
+                Some("coalesce") => Backpressure::CoalescePerPath,
+                _ => Backpressure::Block,
+            };
+
+            builder = builder.max_queued(capacity, backpressure);
+        }
+
+        if matches.is_present("BATCH") {
+            let quiet = value_t!(matches, "BATCH", u64).unwrap_or_else(|e| e.exit());
+            let paths = if matches.is_present("batch_stdin") { BatchPaths::Stdin } else { BatchPaths::Args };
+
+            builder = builder.batch(BatchTrigger {
+                quiet: Duration::from_millis(quiet),
+                paths,
+            });
+        }
+
+        if matches.is_present("notify") {
+            builder = builder.on_exit(desktop::notify_failure);
+        }
+
+        builder.build()
     };
 
-    watcher.register_logger(logger.new(o!("watcher" => 1)));
+    let action = if let Some(target) = target {
+        let name = matches.value_of("SIGNAL").unwrap_or("HUP");
+        let signal = reloader::parse_signal(name).unwrap_or_else(|| {
+            eprintln!("Unknown signal '{}'", name);
+            process::exit(1);
+        });
+
+        Some(Action::Reload(Reloader::new(signal, target)))
+    } else {
+        command.map(|command| {
+            info!(logger, "On change, executing '{:?}'", command);
 
-    let command: Vec<String> = values_t!(matches.values_of("COMMAND"), String).unwrap();
-    let executor = Executor::new(&command);
+            Action::Execute(runner_for(pipeline_for(&command, &config)))
+        })
+    };
 
-    info!(logger, "On change, executing '{:?}'", command);
+    let action = match matches.value_of_os("SCRIPT") {
+        Some(path) => {
+            let actions = matches.values_of("ACTION").into_iter().flatten().map(|action| {
+                let (name, command) = action.split_once('=').unwrap_or_else(|| {
+                    eprintln!("Invalid --action '{}', expected NAME=COMMAND", action);
+                    process::exit(1);
+                });
 
-    while watcher.watch().unwrap() {
-        info!(logger, "Change detected");
+                info!(logger, "The action '{}' executes '{}'", name, command);
 
-        executor.execute().unwrap();
+                (String::from(name), runner_for(step(Pipeline::new(), capture(Executor::new(&shell(command))))))
+            }).collect();
+
+            // Absolute, like the config file's path.
+            let path = env::current_dir().unwrap().join(path);
+
+            if let Some(watcher) = source.watcher() {
+                watcher.add_file(&path).unwrap_or_else(|e| exit_with(&e));
+            }
+
+            Some(scripted(&path, action, actions))
+        },
+        None => action,
+    };
+
+    // Reloading the script changes it.
+    #[cfg(feature = "script")]
+    let mut action = action;
+
+    let use_systemd = matches.is_present("systemd");
+    let mut sink = if let Some(address) = matches.value_of("FORWARD") {
+        forward_sink(address)
+    } else if matches.is_present("json") {
+        json_sink(use_systemd)
+    } else {
+        None
+    };
+
+    let watchdog = if use_systemd { systemd::watchdog_interval() } else { None };
+    let mut last_ping = Instant::now();
+
+    #[cfg(feature = "tui")]
+    let mut tui = if matches.is_present("tui") {
+        Some(Tui::new(vec![source.root().display().to_string()], summary, output.clone()).unwrap_or_else(|e| {
+            eprintln!("Failed to set up the terminal: {}", e);
+            process::exit(1);
+        }))
+    } else {
+        None
+    };
+
+    #[cfg(not(feature = "tui"))]
+    let _ = summary;
+
+    let root = source.root().to_path_buf();
+    let mut source = Scheduler::new(source, root);
+
+    for timer in matches.values_of("EVERY").into_iter().flatten() {
+        let (name, seconds) = timer.split_once('=').unwrap_or((timer, timer));
+        let seconds = seconds.parse::<u64>().ok().filter(|&seconds| seconds > 0).unwrap_or_else(|| {
+            eprintln!("Invalid --every '{}', expected [NAME=]SECONDS", timer);
+            process::exit(1);
+        });
+
+        info!(logger, "Running the timer '{}' every {} seconds", name, seconds);
+
+        source = source.every(name, Duration::from_secs(seconds));
+    }
+
+    #[cfg(feature = "metrics")]
+    let metrics = matches.value_of("METRICS").map(|address| {
+        let metrics = Metrics::new();
+
+        match metrics.serve(address) {
+            Ok(bound) => info!(logger, "Serving metrics at http://{}/metrics", bound),
+            Err(e) => {
+                eprintln!("Failed to listen at '{}': {}", address, e);
+                process::exit(1);
+            },
+        }
+
+        metrics
+    });
+
+    if use_systemd {
+        notify_systemd(&logger, systemd::ready());
+    }
+
+    LOOPING.store(true, Ordering::SeqCst);
+    while !STOP.load(Ordering::SeqCst) {
+        // Published from the event loop, so /healthz turns stale if it hangs.
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(metrics) = &metrics {
+                let runner = action.as_ref().and_then(Action::runner).map(Runner::stats);
+                metrics.publish(source.source_mut().watcher().map(|watcher| watcher.stats()), runner);
+            }
+        }
+
+        #[cfg(feature = "tui")]
+        {
+            if let Some(tui) = &mut tui {
+                while let Some(input) = tui.input() {
+                    match input {
+                        Input::Quit => STOP.store(true, Ordering::SeqCst),
+                        Input::Reload => perform(&action, None, use_systemd, &logger),
+                        Input::TogglePause => {},
+                    }
+                }
+
+                if let Err(e) = tui.draw(action.as_ref().and_then(Action::status)) {
+                    error!(logger, "Failed to draw: {}", e);
+                }
+            }
+        }
+
+        // Pinged from the event loop itself, so systemd notices if it hangs.
+        if let Some(interval) = watchdog {
+            if last_ping.elapsed() >= interval / 2 {
+                notify_systemd(&logger, systemd::watchdog());
+                last_ping = Instant::now();
+            }
+        }
+
+        let event = match source.next_event_timeout(STOP_INTERVAL) {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(e) => exit_with(&e),
+        };
+
+        if let Some(path) = config_path.as_ref().filter(|path| env::current_dir().unwrap().join(&event.path) == **path) {
+            if event.kind == EventKind::Deleted {
+                info!(logger, "Config file removed, keeping its settings");
+                continue;
+            }
+
+            let diff = match reload_config(path, &mut config, source.source_mut(), &mut ignored, &logger) {
+                Some(diff) => diff,
+                None => continue,
+            };
+
+            if diff.command_changed {
+                match (action.as_ref().and_then(Action::runner), command_for(&config)) {
+                    (Some(runner), Some(command)) => {
+                        info!(logger, "On change, executing '{:?}'", command);
+
+                        runner.set_pipeline(pipeline_for(&command, &config));
+                        runner.force();
+                    },
+                    _ => warn!(logger, "The command can only be changed, not added or removed, without restarting"),
+                }
+            }
+
+            continue;
+        }
+
+        #[cfg(feature = "script")]
+        {
+            if let Some(Action::Script(script)) = &mut action {
+                if script.path().is_some_and(|path| env::current_dir().unwrap().join(&event.path) == path) {
+                    reload_script(script, &event, &logger);
+                    continue;
+                }
+            }
+        }
+
+        if ignored.iter().any(|glob| glob.matches(event.relative_path())) {
+            continue;
+        }
+
+        match event.kind {
+            EventKind::Recovered => warn!(logger, "Watcher recovered, changes may have been missed"),
+            EventKind::Storm => match &event.storm {
+                Some(storm) => info!(logger, "{} changes detected in {:?}", storm.count, storm.dirs),
+                None => info!(logger, "Changes detected in {}", event.relative_path().display()),
+            },
+            EventKind::Timer => info!(logger, "Timer '{}' fired", event.timer.as_deref().unwrap_or_default()),
+            _ => info!(logger, "Change detected: {}", event.relative_path().display()),
+        }
+
+        if let Some(sink) = &mut sink {
+            send_json(sink, &event, &logger);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(metrics) = &metrics {
+                metrics.record(&event);
+            }
+        }
+
+        #[cfg(feature = "tui")]
+        {
+            if let Some(tui) = &mut tui {
+                tui.push(&event);
+
+                if tui.is_paused() {
+                    continue;
+                }
+            }
+        }
+
+        if let Some(container) = &container {
+            if let Err(e) = container.sync(&event) {
+                error!(logger, "Failed to copy into container: {}", e);
+            }
+        }
+
+        perform(&action, Some(event), use_systemd, &logger);
+    }
+
+    // Restores the terminal, which exiting would skip.
+    #[cfg(feature = "tui")]
+    drop(tui);
+
+    if use_systemd {
+        notify_systemd(&logger, systemd::stopping());
+    }
+
+    if let Err(e) = source.source().save_state() {
+        eprintln!("Failed to save state: {}", e);
+    }
+
+    if let Some(action) = &action {
+        action.shutdown();
+    }
+
+    if let Some(runner) = action.as_ref().and_then(Action::runner) {
+        let stats = runner.stats();
+
+        if stats.failures > 0 {
+            info!(logger, "The command failed {} of {} times", stats.failures, stats.runs; "retries" => stats.retries);
+        }
+
+        if stats.dropped > 0 || stats.coalesced > 0 {
+            info!(logger, "Dropped {} and merged {} changes the command could not keep up with", stats.dropped, stats.coalesced;
+                  "max_queued" => stats.max_queue_depth);
+        }
+    }
+}
+
+// Applies the changes made to the config file at `path`, except to the
+// command. Returns `None` if nothing changed, or the file could not be read,
+// which keeps the current settings.
+fn reload_config(path: &Path, config: &mut Config, source: &mut Source, ignored: &mut Vec<Glob>,
+                 logger: &slog::Logger) -> Option<ConfigDiff> {
+    let newer = match load_config(path) {
+        Ok(newer) => newer,
+        Err(e) => {
+            error!(logger, "Failed to reload '{}', keeping its settings: {}", path.display(), e);
+            return None;
+        },
+    };
+
+    let diff = config.diff(&newer);
+
+    if diff.is_empty() {
+        return None;
+    }
+
+    info!(logger, "Reloading '{}'", path.display());
+
+    if let Some(watcher) = source.watcher() {
+        for file in &diff.removed_files {
+            watcher.remove_file(file);
+        }
+
+        for file in &diff.added_files {
+            if let Err(e) = watcher.add_file(file) {
+                error!(logger, "Failed to watch '{}': {}", file.display(), e);
+            }
+        }
     }
+
+    if diff.ignore_changed {
+        *ignored = newer.ignore.iter().map(|pattern| Glob::new(pattern)).collect();
+    }
+
+    *config = newer;
+
+    Some(diff)
 }
+
+#[cfg(feature = "config")]
+fn load_config(path: &Path) -> io::Result<Config> { Config::load(path) }
+
+// Never called, since `--config` is refused without the feature.
+#[cfg(not(feature = "config"))]
+fn load_config(_: &Path) -> io::Result<Config> {
+    Err(io::Error::other("--config requires aa to be built with the `config` feature"))
+}
+
+#[cfg(feature = "script")]
+fn scripted(path: &Path, command: Option<Action>, actions: Vec<(String, Runner)>) -> Action {
+    let mut script = Script::load(path).unwrap_or_else(|e| {
+        eprintln!("Failed to load '{}': {}", path.display(), e);
+        process::exit(1);
+    });
+
+    if let Some(Action::Execute(runner)) = command {
+        script = script.action("run", runner);
+    }
+
+    for (name, runner) in actions {
+        script = script.action(&name, runner);
+    }
+
+    Action::Script(Box::new(script))
+}
+
+// Never called, since `--script` is refused without the feature.
+#[cfg(not(feature = "script"))]
+fn scripted(_: &Path, _: Option<Action>, _: Vec<(String, Runner)>) -> Action {
+    eprintln!("--script requires aa to be built with the `script` feature");
+    process::exit(1);
+}
+
+// Recompiles the script after it changed, keeping the current version if
+// that fails or the file was removed.
+#[cfg(feature = "script")]
+fn reload_script(script: &mut Script, event: &WatchEvent, logger: &slog::Logger) {
+    if event.kind == EventKind::Deleted {
+        info!(logger, "Script removed, keeping the current version");
+        return;
+    }
+
+    match script.reload() {
+        Ok(()) => info!(logger, "Reloaded '{}'", event.path.display()),
+        Err(e) => error!(logger, "Failed to reload '{}', keeping the current version: {}", event.path.display(), e),
+    }
+}
+
+impl Action {
+    // The runner of the command, if any.
+    fn runner(&self) -> Option<&Runner> {
+        match self {
+            Action::Execute(runner) => Some(runner),
+            Action::Reload(_) => None,
+            #[cfg(feature = "script")]
+            Action::Script(script) => script.runner("run"),
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    fn status(&self) -> Option<Status> { self.runner().map(Runner::status) }
+
+    // Terminates the commands still running, which Ctrl-C does not reach.
+    fn shutdown(&self) {
+        match self {
+            Action::Execute(runner) => runner.shutdown(GRACE_PERIOD),
+            Action::Reload(_) => {},
+            #[cfg(feature = "script")]
+            Action::Script(script) => script.runners().for_each(|runner| runner.shutdown(GRACE_PERIOD)),
+        }
+    }
+}
+
+// Runs the command or signals the process, for the event or else because
+// the user asked for it.
+fn perform(action: &Option<Action>, event: Option<WatchEvent>, use_systemd: bool, logger: &slog::Logger) {
+    match action {
+        Some(Action::Execute(runner)) => match event {
+            Some(event) => runner.trigger(event),
+            None => runner.force(),
+        },
+        #[cfg(feature = "script")]
+        Some(Action::Script(script)) => match event {
+            Some(event) => {
+                if let Err(e) = script.handle(&event) {
+                    error!(logger, "Script failed for '{}': {}", event.relative_path().display(), e);
+                }
+            },
+            None => script.runner("run").into_iter().for_each(Runner::force),
+        },
+        Some(Action::Reload(reloader)) => {
+            if use_systemd {
+                notify_systemd(logger, systemd::reloading());
+            }
+
+            if let Err(e) = reloader.reload() {
+                error!(logger, "Failed to signal process: {}", e);
+            }
+
+            if use_systemd {
+                notify_systemd(logger, systemd::ready());
+            }
+        },
+        None => {},
+    }
+}
+
+fn shell(command: &str) -> [String; 3] { [String::from("sh"), String::from("-c"), String::from(command)] }
+
+fn print_plan(plan: &Plan) {
+    for dir in &plan.dirs {
+        println!("{}", dir.display());
+    }
+
+    print!("{} directories would be watched", plan.watches());
+
+    match (plan.limit, plan.in_use) {
+        (Some(limit), Some(in_use)) => println!(", {} of {} watches are in use", in_use, limit),
+        (Some(limit), None) => println!(", the limit is {} watches", limit),
+        _ => println!(),
+    }
+
+    if plan.fits() == Some(false) {
+        println!("This exceeds the inotify watch limit; raise it with `sysctl fs.inotify.max_user_watches=<n>`");
+    }
+}
+
+// Lists the compiler's errors before its warnings, followed by the output of
+// the program or tests that cargo ran, if any.
+fn report_cargo(status: ExitStatus, output: &[u8]) {
+    let output = String::from_utf8_lossy(output);
+    let diagnostics = Diagnostic::parse_all(&output);
+
+    let errors: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.severity == Severity::Error).collect();
+    // Summaries like "`aa` (lib) generated 2 warnings" have no location.
+    let warnings: Vec<&Diagnostic> = diagnostics.iter()
+                                                .filter(|d| d.severity == Severity::Warning && d.location.is_some())
+                                                .collect();
+
+    for diagnostic in errors.iter().chain(&warnings) {
+        eprintln!("{}", diagnostic);
+    }
+
+    let mut ran = output.lines()
+                        .skip_while(|line| !line.trim_start().starts_with("Running "))
+                        .peekable();
+
+    if ran.peek().is_some() {
+        for line in ran {
+            eprintln!("{}", line);
+        }
+    } else if !status.success() && errors.is_empty() {
+        eprintln!("{}", output);
+    }
+
+    let located = errors.iter().filter(|d| d.location.is_some()).count();
+
+    match (status.success(), located, warnings.len()) {
+        (true, _, 0) => eprintln!("cargo: ok"),
+        (true, _, w) => eprintln!("cargo: ok, {} warning(s)", w),
+        (false, e, w) => eprintln!("cargo: failed, {} error(s), {} warning(s)", e, w),
+    }
+}
+
+fn notify_systemd(logger: &slog::Logger, result: io::Result<bool>) {
+    if let Err(e) = result {
+        warn!(logger, "Failed to notify systemd: {}", e);
+    }
+}
+
+fn exit_with(error: &WatcherError) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
+
+// Serves the events on the socket passed by systemd, if any, or else prints
+// them to stdout.
+#[cfg(feature = "serde")]
+fn json_sink(use_systemd: bool) -> Option<JsonSink<Box<dyn Write + Send>>> {
+    if let (true, Some(fd)) = (use_systemd, systemd::listen_fds().first()) {
+        match Listener::from_raw_fd(*fd) {
+            Ok(listener) => return Some(JsonSink::new(Box::new(listener))),
+            Err(e) => {
+                eprintln!("Failed to use the activated socket: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+
+    Some(JsonSink::new(Box::new(io::stdout())))
+}
+
+#[cfg(feature = "serde")]
+fn forward_sink(address: &str) -> Option<JsonSink<Box<dyn Write + Send>>> {
+    match remote::sender(&Address::parse(address)) {
+        Ok(sink) => Some(sink),
+        Err(e) => {
+            eprintln!("Failed to connect to '{}': {}", address, e);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(feature = "serde")]
+fn receiver(address: &str, path: &Path, rsync: Option<&str>, logger: &slog::Logger) -> Source {
+    let mut receiver = Receiver::bind(&Address::parse(address), path).unwrap_or_else(|e| {
+        eprintln!("Failed to listen at '{}': {}", address, e);
+        process::exit(1);
+    });
+
+    if let Some(source) = rsync {
+        receiver = receiver.rsync(source);
+    }
+
+    Source::Receiver(receiver.logger(logger.new(o!("receiver" => 1))))
+}
+
+#[cfg(feature = "serde")]
+fn send_json(sink: &mut JsonSink<Box<dyn Write + Send>>, event: &WatchEvent, logger: &slog::Logger) {
+    if let Err(e) = sink.send(event) {
+        error!(logger, "Failed to send event: {}", e);
+        process::exit(1);
+    }
+}
+
+// Without serde there is no way to serialize events, so `--json` is refused.
+#[cfg(not(feature = "serde"))]
+fn json_sink(_: bool) -> Option<()> {
+    eprintln!("--json requires aa to be built with the `serde` feature");
+    process::exit(1);
+}
+
+#[cfg(not(feature = "serde"))]
+fn forward_sink(_: &str) -> Option<()> {
+    eprintln!("--forward requires aa to be built with the `serde` feature");
+    process::exit(1);
+}
+
+#[cfg(not(feature = "serde"))]
+fn receiver(_: &str, _: &Path, _: Option<&str>, _: &slog::Logger) -> Source {
+    eprintln!("--receive requires aa to be built with the `serde` feature");
+    process::exit(1);
+}
+
+#[cfg(not(feature = "serde"))]
+fn send_json(_: &mut (), _: &WatchEvent, _: &slog::Logger) {}
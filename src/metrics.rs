@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::events::{EventKind, WatchEvent};
+use crate::stats::{RunnerStats, WatcherStats};
+
+/// How long after the last `publish` `/healthz` reports the process as
+/// unhealthy, e.g. because its event loop hangs.
+pub const STALE_AFTER: Duration = Duration::from_secs(30);
+
+// How long a client gets for each read of its request, and write of the
+// response.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The most of a request that is read, with its headers.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+// The threads answering clients, so a few slow ones only hold up the others
+// until they time out.
+const WORKERS: usize = 4;
+
+const KINDS: &[EventKind] = &[
+    EventKind::Created,
+    EventKind::Modified,
+    EventKind::Deleted,
+    EventKind::Rescan,
+    EventKind::Storm,
+    EventKind::Timer,
+    EventKind::Recovered,
+];
+
+#[derive(Default)]
+struct Published {
+    watcher: Option<WatcherStats>,
+    runner: Option<RunnerStats>,
+    // The events handled, per kind.
+    processed: HashMap<EventKind, u64>,
+    updated: Option<Instant>,
+}
+
+/// Serves `/healthz` and Prometheus metrics at `/metrics` over HTTP, from
+/// the statistics last published to it. Clones share the statistics, so the
+/// event loop can publish while another thread serves them.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    published: Arc<Mutex<Published>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics { Metrics::default() }
+
+    /// Replaces the statistics, and marks the process as healthy until
+    /// `STALE_AFTER` from now. Either may be absent, e.g. the watcher's when
+    /// receiving forwarded events.
+    pub fn publish(&self, watcher: Option<WatcherStats>, runner: Option<RunnerStats>) {
+        let mut published = self.published.lock().unwrap();
+
+        published.watcher = watcher;
+        published.runner = runner;
+        published.updated = Some(Instant::now());
+    }
+
+    /// Counts the event as handled, after any filtering.
+    pub fn record(&self, event: &WatchEvent) {
+        *self.published.lock().unwrap().processed.entry(event.kind).or_insert(0) += 1;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.published.lock().unwrap().updated.is_some_and(|updated| updated.elapsed() < STALE_AFTER)
+    }
+
+    /// The metrics in Prometheus' text format.
+    pub fn render(&self) -> String {
+        let published = self.published.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "aa_events_processed_total", "counter", "Events handled, after filtering.");
+        for kind in KINDS {
+            let count = published.processed.get(kind).copied().unwrap_or(0);
+            let _ = writeln!(out, "aa_events_processed_total{{kind=\"{}\"}} {}", kind.name(), count);
+        }
+
+        if let Some(stats) = &published.watcher {
+            header(&mut out, "aa_watcher_events_total", "counter", "Changes seen by the watcher, before filtering.");
+            for (kind, count) in [("created", stats.created), ("modified", stats.modified), ("deleted", stats.deleted)] {
+                let _ = writeln!(out, "aa_watcher_events_total{{kind=\"{}\"}} {}", kind, count);
+            }
+
+            metric(&mut out, "aa_watcher_overflows_total", "counter", "Overflows of the kernel's event queue.",
+                   stats.overflows);
+            metric(&mut out, "aa_watcher_recoveries_total", "counter", "Recoveries of the watcher from errors.",
+                   stats.recoveries);
+            metric(&mut out, "aa_watch_descriptors", "gauge", "Inotify watch descriptors in use.", stats.watches);
+
+            if let Some(secs) = stats.last_event.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+                metric(&mut out, "aa_last_event_timestamp_seconds", "gauge", "When the watcher saw the latest change.",
+                       secs.as_secs());
+            }
+        }
+
+        if let Some(stats) = &published.runner {
+            metric(&mut out, "aa_command_runs_total", "counter", "Executions of the command, including retries.",
+                   stats.runs);
+            metric(&mut out, "aa_command_failures_total", "counter", "Failed executions.", stats.failures);
+            metric(&mut out, "aa_command_retries_total", "counter", "Executions retrying a failure.", stats.retries);
+            metric(&mut out, "aa_command_restarts_total", "counter", "Executions terminated to restart the command.",
+                   stats.restarts);
+            metric(&mut out, "aa_command_consecutive_failures", "gauge", "Failures since the last success.",
+                   stats.consecutive_failures);
+            metric(&mut out, "aa_queue_depth", "gauge", "Events waiting to be run for.", stats.queue_depth);
+            metric(&mut out, "aa_queue_dropped_total", "counter", "Waiting events dropped by backpressure.",
+                   stats.dropped);
+            metric(&mut out, "aa_queue_coalesced_total", "counter", "Waiting events merged by backpressure.",
+                   stats.coalesced);
+        }
+
+        out
+    }
+
+    /// Listens at `address` on a few background threads, each answering one
+    /// client at a time, so a slow one cannot hold up the others' probes.
+    /// Returns the address bound, e.g. for port 0.
+    pub fn serve<A: ToSocketAddrs>(&self, address: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let bound = listener.local_addr()?;
+
+        for _ in 0..WORKERS {
+            let listener = listener.try_clone()?;
+            let metrics = self.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    // A client misbehaving only loses its own response.
+                    let _ = metrics.respond(stream);
+                }
+            });
+        }
+
+        Ok(bound)
+    }
+
+    fn respond(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+        let mut request = String::new();
+
+        reader.read_line(&mut request)?;
+
+        // The headers are not needed, but are read so the client is not reset.
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+
+        let (status, content_type, body) = match path.split('?').next() {
+            Some("/healthz") if self.is_healthy() => ("200 OK", "text/plain", String::from("ok\n")),
+            Some("/healthz") => ("503 Service Unavailable", "text/plain", String::from("stale\n")),
+            Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", self.render()),
+            _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+        };
+
+        let mut stream = reader.into_inner().into_inner();
+
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, content_type, body.len(), body)?;
+        stream.flush()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn metric<V: std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, value: V) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalled_clients_do_not_hold_up_probes() {
+        let metrics = Metrics::new();
+        metrics.publish(None, None);
+
+        let address = metrics.serve("127.0.0.1:0").unwrap();
+        let _stalled: Vec<TcpStream> = (1..WORKERS).map(|_| TcpStream::connect(address).unwrap()).collect();
+
+        let mut probe = TcpStream::connect(address).unwrap();
+        probe.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        probe.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        probe.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("ok\n"), "{}", response);
+    }
+}
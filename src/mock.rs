@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::WatcherError;
+use crate::events::{EventKind, Origin, Storm, WatchEvent};
+use crate::source::EventSource;
+
+/// An `EventSource` reporting the events a test injects, without touching
+/// the filesystem or waiting.
+///
+/// Events are reported in the order they were injected, and in one batch
+/// until `end_batch` is called. Once all of them were, the mock is
+/// finished, so a `Dispatcher::run` returns `Ok(())`, `next_event_timeout`
+/// returns `Ok(None)` right away, and `next_event` fails with an
+/// `UnexpectedEof` I/O error.
+#[derive(Clone, Debug, Default)]
+pub struct MockWatcher {
+    root: PathBuf,
+    // Never contains an empty batch.
+    batches: VecDeque<Vec<WatchEvent>>,
+    // Whether the next event starts a new batch.
+    batch_ended: bool,
+}
+
+impl MockWatcher {
+    /// Reports events below `root`, which does not have to exist.
+    pub fn new<P: AsRef<Path>>(root: P) -> MockWatcher {
+        MockWatcher {
+            root: root.as_ref().to_path_buf(),
+            batches: VecDeque::new(),
+            batch_ended: false,
+        }
+    }
+
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// Injects an event as is, e.g. one read from a `Journal`.
+    pub fn push(&mut self, event: WatchEvent) -> &mut MockWatcher {
+        match self.batches.back_mut() {
+            Some(batch) if !self.batch_ended => batch.push(event),
+            _ => self.batches.push_back(vec![event]),
+        }
+
+        self.batch_ended = false;
+        self
+    }
+
+    /// Reports the events injected after this in a separate batch.
+    pub fn end_batch(&mut self) -> &mut MockWatcher {
+        self.batch_ended = true;
+        self
+    }
+
+    /// Injects a created file at `path`, relative to the root.
+    pub fn create<P: AsRef<Path>>(&mut self, path: P) -> &mut MockWatcher {
+        self.change(EventKind::Created, path.as_ref(), false)
+    }
+
+    pub fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut MockWatcher {
+        self.change(EventKind::Created, path.as_ref(), true)
+    }
+
+    pub fn modify<P: AsRef<Path>>(&mut self, path: P) -> &mut MockWatcher {
+        self.change(EventKind::Modified, path.as_ref(), false)
+    }
+
+    pub fn delete<P: AsRef<Path>>(&mut self, path: P) -> &mut MockWatcher {
+        self.change(EventKind::Deleted, path.as_ref(), false)
+    }
+
+    pub fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut MockWatcher {
+        self.change(EventKind::Deleted, path.as_ref(), true)
+    }
+
+    /// Injects the event a watcher reports after its event queue overflowed.
+    pub fn rescan(&mut self) -> &mut MockWatcher {
+        let root = self.root.clone();
+
+        self.change(EventKind::Rescan, &root, true)
+    }
+
+    /// Injects a storm of `count` changes in `dirs`, relative to the root.
+    pub fn storm(&mut self, count: usize, dirs: &[&str]) -> &mut MockWatcher {
+        let dirs = dirs.iter().map(|dir| self.root.join(dir)).collect();
+        let root = self.root.clone();
+
+        self.push(WatchEvent {
+            kind: EventKind::Storm,
+            path: root.clone(),
+            root,
+            is_dir: true,
+            metadata: None,
+            storm: Some(Storm { count, dirs }),
+            origin: Origin::External,
+            timer: None,
+        })
+    }
+
+    /// The number of events not reported yet.
+    pub fn len(&self) -> usize { self.batches.iter().map(Vec::len).sum() }
+
+    pub fn is_empty(&self) -> bool { self.batches.is_empty() }
+
+    fn change(&mut self, kind: EventKind, path: &Path, is_dir: bool) -> &mut MockWatcher {
+        self.push(WatchEvent {
+            kind,
+            path: self.root.join(path),
+            root: self.root.clone(),
+            is_dir,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        })
+    }
+}
+
+impl EventSource for MockWatcher {
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError> {
+        self.next_event_timeout(Duration::from_secs(0))?.ok_or_else(exhausted)
+    }
+
+    fn next_event_timeout(&mut self, _: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        let batch = match self.batches.front_mut() {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+
+        let event = batch.remove(0);
+
+        if batch.is_empty() {
+            self.batches.pop_front();
+        }
+
+        Ok(Some(event))
+    }
+
+    fn next_batch(&mut self) -> Result<Vec<WatchEvent>, WatcherError> {
+        self.batches.pop_front().ok_or_else(exhausted)
+    }
+
+    fn is_finished(&self) -> bool { self.batches.is_empty() }
+}
+
+fn exhausted() -> WatcherError {
+    WatcherError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "No more mock events"))
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// What happens to events that arrive while a watcher is paused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayPolicy {
+    /// Drop them, while still keeping the watched directories up to date.
+    Discard,
+    /// Deliver them once the watcher is resumed.
+    Replay,
+}
+
+struct PauseState {
+    paused: bool,
+    replay: ReplayPolicy,
+}
+
+/// Pauses and resumes event delivery of a `Watcher`, possibly from another
+/// thread than the one blocked in `Watcher::next_event`.
+///
+/// While paused, the watcher stops reading from inotify, so the kernel queues
+/// any events until it is resumed.
+#[derive(Clone)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<PauseState>, Condvar)>,
+}
+
+impl PauseHandle {
+    pub fn new() -> PauseHandle {
+        PauseHandle {
+            state: Arc::new((Mutex::new(PauseState {
+                paused: false,
+                replay: ReplayPolicy::Replay,
+            }), Condvar::new())),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.state.0.lock().unwrap().paused = true;
+    }
+
+    pub fn resume(&self, replay: ReplayPolicy) {
+        let (state, resumed) = &*self.state;
+        let mut state = state.lock().unwrap();
+
+        state.paused = false;
+        state.replay = replay;
+        resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool { self.state.0.lock().unwrap().paused }
+
+    // Blocks while paused. Returns how events read during the pause are to be
+    // treated, or `None` if the watcher was not paused.
+    pub(crate) fn wait(&self) -> Option<ReplayPolicy> {
+        let (state, resumed) = &*self.state;
+        let mut state = state.lock().unwrap();
+
+        if !state.paused {
+            return None;
+        }
+
+        while state.paused {
+            state = resumed.wait(state).unwrap();
+        }
+
+        Some(state.replay)
+    }
+}
+
+impl Default for PauseHandle {
+    fn default() -> PauseHandle { PauseHandle::new() }
+}
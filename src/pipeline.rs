@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use crate::events::{EventKind, WatchEvent};
+use crate::filter::Filter;
+use crate::glob::Glob;
+use crate::runner::Runner;
+
+/// A build step, e.g. compiling Sass, run when one of its inputs changes.
+/// Inputs and outputs are patterns relative to the watched root, see `Glob`.
+pub struct Stage {
+    name: String,
+    inputs: Vec<Glob>,
+    outputs: Vec<Glob>,
+    runner: Runner,
+}
+
+impl Stage {
+    pub fn new(name: &str, runner: Runner) -> Stage {
+        Stage {
+            name: String::from(name),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            runner,
+        }
+    }
+
+    /// Runs the stage when a path matching `pattern` changes. Can be called
+    /// repeatedly.
+    pub fn input(mut self, pattern: &str) -> Stage {
+        self.inputs.push(Glob::new(pattern));
+        self
+    }
+
+    /// Declares that the stage writes paths matching `pattern`. Changes to
+    /// them never trigger a stage. Can be called repeatedly.
+    pub fn output(mut self, pattern: &str) -> Stage {
+        self.outputs.push(Glob::new(pattern));
+        self
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    /// Whether the stage writes `path`, relative to the watched root.
+    pub fn produces<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.outputs.iter().any(|glob| glob.matches(path.as_ref()))
+    }
+
+    /// Whether the event concerns one of the stage's inputs. `Rescan`,
+    /// `Recovered` and `Storm` events may, so they trigger every stage.
+    pub fn is_triggered_by(&self, event: &WatchEvent) -> bool {
+        match event.kind {
+            EventKind::Rescan | EventKind::Recovered | EventKind::Storm => true,
+            _ => self.inputs.iter().any(|glob| glob.matches(event.relative_path())),
+        }
+    }
+}
+
+/// Triggers the stages whose inputs changed, ignoring changes to the outputs
+/// of any stage, so that a stage writing into the watched tree does not
+/// trigger itself or another stage in a loop.
+///
+/// Building the watcher with `Pipeline::filter` drops those changes before
+/// they count towards a storm.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline { Pipeline::default() }
+
+    pub fn stage(mut self, stage: Stage) -> Pipeline {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn stages(&self) -> &[Stage] { &self.stages }
+
+    /// Whether the event is for a path written by one of the stages.
+    pub fn is_output(&self, event: &WatchEvent) -> bool {
+        self.stages.iter().any(|stage| stage.produces(event.relative_path()))
+    }
+
+    /// A filter dropping events for the outputs of all stages, for
+    /// `WatcherBuilder::filter`.
+    pub fn filter(&self) -> Filter {
+        let outputs: Vec<Glob> = self.stages.iter().flat_map(|stage| stage.outputs.iter().cloned()).collect();
+
+        !Filter::custom(move |event| outputs.iter().any(|glob| glob.matches(event.relative_path())))
+    }
+
+    /// Triggers every stage the event is an input of, unless it is an output,
+    /// and returns their names.
+    pub fn trigger(&self, event: &WatchEvent) -> Vec<&str> {
+        if self.is_output(event) {
+            return Vec::new();
+        }
+
+        self.stages.iter()
+                   .filter(|stage| stage.is_triggered_by(event))
+                   .map(|stage| {
+                       stage.runner.trigger(event.clone());
+
+                       stage.name()
+                   })
+                   .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::mock::MockWatcher;
+    use crate::source::EventSource;
+
+    fn stage(name: &str) -> Stage { Stage::new(name, Runner::builder(Executor::new(&[String::from("true")])).build()) }
+
+    #[test]
+    fn triggers_stages_by_input_but_not_for_outputs() {
+        let pipeline = Pipeline::new()
+            .stage(stage("sass").input("styles/*.scss").output("public/*.css"))
+            .stage(stage("minify").input("public/*.css").output("public/*.min.css"));
+
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("styles/site.scss").modify("public/site.css").modify("README.md").rescan();
+
+        let triggered: Vec<Vec<&str>> = mock.next_batch().unwrap().iter().map(|event| pipeline.trigger(event)).collect();
+
+        assert_eq!(triggered, [vec!["sass"], vec![], vec![], vec!["sass", "minify"]]);
+    }
+
+    #[test]
+    fn filter_drops_outputs() {
+        let pipeline = Pipeline::new().stage(stage("sass").input("styles/*.scss").output("public/*.css"));
+
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("styles/site.scss").modify("public/site.css");
+
+        let filter = pipeline.filter();
+        let kept: Vec<bool> = mock.next_batch().unwrap().iter().map(|event| filter.matches(event)).collect();
+
+        assert_eq!(kept, [true, false]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use libc::{c_int, pid_t};
+
+/// The process a `Reloader` delivers its signal to.
+pub enum Target {
+    Pid(pid_t),
+    PidFile(String),
+}
+
+/// Sends a signal to an already running process instead of executing a
+/// command, for daemons that reload their configuration on e.g. SIGHUP.
+pub struct Reloader {
+    signal: c_int,
+    target: Target,
+}
+
+impl Reloader {
+    pub fn new(signal: c_int, target: Target) -> Reloader {
+        Reloader {
+            signal,
+            target,
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), Error> {
+        let pid = self.pid()?;
+
+        if unsafe { libc::kill(pid, self.signal) } == -1 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // The pidfile is re-read on every reload, so a restarted daemon is found.
+    // Pids below 1 are refused, since kill(2) signals whole groups of
+    // processes for them.
+    fn pid(&self) -> Result<pid_t, Error> {
+        match &self.target {
+            Target::Pid(pid) if *pid > 0 => Ok(*pid),
+            Target::Pid(pid) => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid pid: {}", pid))),
+            Target::PidFile(file) => {
+                let contents = fs::read_to_string(file)?;
+
+                contents.trim().parse().ok().filter(|pid| *pid > 0).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData,
+                               format!("Invalid pid in '{}': '{}'", file, contents.trim()))
+                })
+            }
+        }
+    }
+}
+
+/// Parses a signal given by name (`HUP`, `SIGUSR1`) or by number (`1`),
+/// up to `SIGRTMAX`.
+pub fn parse_signal(name: &str) -> Option<c_int> {
+    if let Ok(number) = name.parse() {
+        return Some(number).filter(|number| (1..=libc::SIGRTMAX()).contains(number));
+    }
+
+    let name = name.to_uppercase();
+
+    let signal = match name.trim_start_matches("SIG") {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    };
+
+    Some(signal)
+}
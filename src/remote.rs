@@ -0,0 +1,405 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::WatcherError;
+use crate::events::{EventKind, WatchEvent};
+use crate::log::{Level, WatcherLog};
+use crate::sink::JsonSink;
+use crate::watchers;
+
+/// Where events are forwarded to: a Unix socket if the address contains a
+/// `/`, or else a TCP `host:port`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Address {
+        if address.contains('/') {
+            Address::Unix(PathBuf::from(address))
+        } else {
+            Address::Tcp(String::from(address))
+        }
+    }
+}
+
+/// Connects to a `Receiver` listening at `address`. Events sent to the
+/// returned sink are replayed on the receiving side.
+pub fn sender(address: &Address) -> io::Result<JsonSink<Box<dyn Write + Send>>> {
+    let stream: Box<dyn Write + Send> = match address {
+        Address::Tcp(address) => Box::new(TcpStream::connect(address.as_str())?),
+        Address::Unix(path) => Box::new(UnixStream::connect(path)?),
+    };
+
+    Ok(JsonSink::new(stream))
+}
+
+enum Socket {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Receives events from a sender, e.g. a watcher on the machine where files
+/// are edited, and returns them as if they happened below a local root.
+///
+/// One sender is served at a time. Once it disconnects, or sends something
+/// other than an event, the next one is accepted. Events for paths outside
+/// the sender's root, or with `..` components, are dropped, since senders are
+/// not authenticated.
+pub struct Receiver {
+    socket: Socket,
+    sender: Option<(RawFd, BufReader<Box<dyn Read + Send>>)>,
+    root: PathBuf,
+    rsync: Option<String>,
+    logger: Option<Arc<dyn WatcherLog>>,
+}
+
+impl Receiver {
+    /// Listens at `address` for senders. Events are mapped onto `root` by
+    /// their path relative to the sender's root.
+    pub fn bind<P: AsRef<Path>>(address: &Address, root: P) -> io::Result<Receiver> {
+        let socket = match address {
+            Address::Tcp(address) => Socket::Tcp(TcpListener::bind(address.as_str())?),
+            Address::Unix(path) => Socket::Unix(UnixListener::bind(path)?),
+        };
+
+        Ok(Receiver {
+            socket,
+            sender: None,
+            root: root.as_ref().to_path_buf(),
+            rsync: None,
+            logger: None,
+        })
+    }
+
+    /// Copies the content of changed paths from `source` with rsync before
+    /// returning their events, e.g. from `laptop:/home/me/project`. Deleted
+    /// paths are removed locally, and the whole tree is synchronized for
+    /// `Rescan`, `Recovered` and `Storm` events. Other events for the root
+    /// itself, or for paths below a symlink, are not synchronized.
+    pub fn rsync(mut self, source: &str) -> Receiver {
+        self.rsync = Some(String::from(source.trim_end_matches('/')));
+        self
+    }
+
+    pub fn log<L: WatcherLog + 'static>(mut self, logger: L) -> Receiver {
+        self.logger = Some(Arc::new(logger));
+        self
+    }
+
+    #[cfg(feature = "slog")]
+    pub fn logger(self, logger: slog::Logger) -> Receiver { self.log(logger) }
+
+    /// The local directory events are mapped onto.
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// Blocks until an event is received and returns it.
+    pub fn next_event(&mut self) -> Result<WatchEvent, WatcherError> {
+        loop {
+            if let Some(event) = self.next_event_timeout(Duration::from_secs(3600))? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Like `next_event`, but returns `Ok(None)` if no event is received
+    /// within `timeout`, a sender connected or disconnected, or an event was
+    /// dropped.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        let (fd, reader) = match &mut self.sender {
+            Some((fd, reader)) => (*fd, reader),
+            None => {
+                if watchers::readable(self.listener_fd(), timeout)? {
+                    self.accept()?;
+                }
+
+                return Ok(None);
+            },
+        };
+
+        if reader.buffer().is_empty() && !watchers::readable(fd, timeout)? {
+            return Ok(None);
+        }
+
+        let mut line = String::new();
+
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                self.sender = None;
+                self.log_at(Level::Info, format_args!("Sender disconnected"));
+
+                return Ok(None);
+            },
+            Err(e) => {
+                self.sender = None;
+                self.log_at(Level::Warning, format_args!("Dropping the sender after a failed read: {}", e));
+
+                return Ok(None);
+            },
+            Ok(_) => {},
+        }
+
+        let event = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                self.sender = None;
+                self.log_at(Level::Warning, format_args!("Dropping the sender after an invalid event: {}", e));
+
+                return Ok(None);
+            },
+        };
+
+        let event = match self.localize(event) {
+            Some(event) => event,
+            None => return Ok(None),
+        };
+
+        if self.rsync.is_some() {
+            self.sync(&event);
+        }
+
+        Ok(Some(event))
+    }
+
+    fn listener_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Unix(listener) => listener.as_raw_fd(),
+            Socket::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        let (fd, stream): (RawFd, Box<dyn Read + Send>) = match &self.socket {
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+
+                self.log_at(Level::Info, format_args!("Sender connected"));
+
+                (stream.as_raw_fd(), Box::new(stream))
+            },
+            Socket::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+
+                self.log_at(Level::Info, format_args!("Sender connected from {}", addr));
+
+                (stream.as_raw_fd(), Box::new(stream))
+            },
+        };
+
+        self.sender = Some((fd, BufReader::new(stream)));
+
+        Ok(())
+    }
+
+    // Moves the event from the sender's root to the local one, or drops it if
+    // any of its paths would end up outside the local root.
+    fn localize(&self, mut event: WatchEvent) -> Option<WatchEvent> {
+        let path = match self.local_path(&event.path, &event.root) {
+            Some(path) => path,
+            None => {
+                self.log_at(Level::Warning, format_args!("Dropping an event for {:?} outside the sender's root {:?}",
+                                                         event.path, event.root));
+                return None;
+            },
+        };
+
+        if let Some(storm) = &mut event.storm {
+            for dir in &mut storm.dirs {
+                match self.local_path(dir, &event.root) {
+                    Some(local) => *dir = local,
+                    None => {
+                        self.log_at(Level::Warning, format_args!("Dropping a storm for {:?} outside the sender's \
+                                                                 root {:?}", dir, event.root));
+                        return None;
+                    },
+                }
+            }
+        }
+
+        event.path = path;
+        event.root = self.root.clone();
+        Some(event)
+    }
+
+    // The local path for the sender's `path` below its `root`, if it is below
+    // it without `..` components.
+    fn local_path(&self, path: &Path, root: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(root).ok()?;
+
+        if !relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+
+        Some(self.root.join(relative)).filter(|local| local.starts_with(&self.root))
+    }
+
+    // The path relative to the root, if syncing it cannot affect anything
+    // outside the root: it is below the root, without `..` components, and
+    // not below a symlink. Checked again, since syncing removes files.
+    fn sync_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+
+        if relative.components().any(|component| component == Component::ParentDir) {
+            return None;
+        }
+
+        let mut dir = self.root.clone();
+
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+
+            if fs::symlink_metadata(&dir).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                return None;
+            }
+        }
+
+        Some(relative)
+    }
+
+    fn sync(&self, event: &WatchEvent) {
+        let relative = match self.sync_path(&event.path) {
+            Some(relative) => relative,
+            None => {
+                self.log_at(Level::Warning, format_args!("Not syncing {:?} outside {:?} or below a symlink",
+                                                         event.path, self.root));
+                return;
+            },
+        };
+
+        let result = match event.kind {
+            EventKind::Rescan | EventKind::Recovered | EventKind::Storm => self.run_rsync(Path::new(""), &self.root, true),
+            EventKind::Timer => Ok(()),
+            // Would replace or remove the whole tree.
+            _ if relative.as_os_str().is_empty() => {
+                self.log_at(Level::Warning, format_args!("Not syncing a {} event for the root {:?} itself",
+                                                         event.kind.name(), self.root));
+                return;
+            },
+            EventKind::Created | EventKind::Modified if event.is_dir => fs::create_dir_all(&event.path),
+            EventKind::Created | EventKind::Modified => {
+                match event.path.parent() {
+                    Some(parent) => fs::create_dir_all(parent).and_then(|_| self.run_rsync(relative, &event.path, false)),
+                    None => self.run_rsync(relative, &event.path, false),
+                }
+            },
+            EventKind::Deleted if event.is_dir => fs::remove_dir_all(&event.path),
+            EventKind::Deleted => fs::remove_file(&event.path),
+        };
+
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && event.kind == EventKind::Deleted => {},
+            Err(e) => self.log_at(Level::Warning, format_args!("Failed to sync {:?}: {}", event.path, e)),
+            Ok(()) => {},
+        }
+    }
+
+    // Copies `relative` below the rsync source to `destination`. With `tree`,
+    // the directory is mirrored, including deletions. The paths are passed
+    // as they are, without the remote shell splitting them again.
+    fn run_rsync(&self, relative: &Path, destination: &Path, tree: bool) -> io::Result<()> {
+        let mut source = OsString::from(self.rsync.as_deref().unwrap_or_default());
+        let mut command = Command::new("rsync");
+
+        source.push("/");
+        source.push(relative);
+        command.arg("-a").arg("--protect-args").stdin(Stdio::null());
+
+        if tree {
+            let mut destination = destination.as_os_str().to_os_string();
+
+            destination.push("/");
+            command.arg("--delete").arg(source).arg(destination);
+        } else {
+            command.arg(source).arg(destination);
+        }
+
+        let status = command.status().map_err(|e| io::Error::new(e.kind(), format!("Failed to run rsync: {}", e)))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("rsync exited with {}", status)))
+        }
+    }
+
+    fn log_at(&self, level: Level, message: std::fmt::Arguments) {
+        if let Some(logger) = &self.logger {
+            logger.log(level, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Origin;
+    use std::os::unix::fs::symlink;
+    use std::{env, process};
+
+    fn event(kind: EventKind, path: PathBuf, root: &Path, is_dir: bool) -> WatchEvent {
+        WatchEvent {
+            kind,
+            path,
+            root: root.to_path_buf(),
+            is_dir,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    fn receiver(name: &str) -> (Receiver, PathBuf) {
+        let dir = env::temp_dir().join(format!("aa-remote-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+
+        (Receiver::bind(&Address::Unix(dir.join("socket")), dir.join("root")).unwrap(), dir)
+    }
+
+    #[test]
+    fn localize_drops_paths_escaping_the_root() {
+        let (receiver, dir) = receiver("localize");
+        let sender = Path::new("/home/me/project");
+
+        let local = receiver.localize(event(EventKind::Modified, sender.join("src/a"), sender, false)).unwrap();
+        assert_eq!(local.path, dir.join("root/src/a"));
+        assert_eq!(local.root, dir.join("root"));
+
+        assert!(receiver.localize(event(EventKind::Modified, sender.join("../x"), sender, false)).is_none());
+        assert!(receiver.localize(event(EventKind::Modified, PathBuf::from("/etc/passwd"), sender, false)).is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sync_does_not_delete_the_root_or_through_symlinks() {
+        let (receiver, dir) = receiver("sync");
+        let root = dir.join("root");
+        fs::write(dir.join("outside/kept"), "").unwrap();
+        symlink(dir.join("outside"), root.join("link")).unwrap();
+
+        receiver.sync(&event(EventKind::Deleted, root.clone(), &root, true));
+        receiver.sync(&event(EventKind::Deleted, root.join("link/kept"), &root, false));
+
+        assert!(root.join("sub").exists());
+        assert!(dir.join("outside/kept").exists());
+
+        receiver.sync(&event(EventKind::Deleted, root.join("sub"), &root, true));
+        assert!(!root.join("sub").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
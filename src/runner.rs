@@ -0,0 +1,1241 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::io::Error;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::events::{EventKind, WatchEvent};
+use crate::executor::{arg_size, Execution, Executor};
+use crate::glob::Glob;
+use crate::log::{Level, WatcherLog};
+use crate::stats::RunnerStats;
+
+// Macro alias for an info message to first check for a logger.
+macro_rules! runner_info(
+    ($r:expr, $($args:tt)+) => {
+        if let Some(logger) = &$r.logger {
+            logger.log(Level::Info, format_args!($($args)+))
+        }
+    };
+);
+
+// Macro alias for an error to first check for a logger.
+macro_rules! runner_error(
+    ($r:expr, $($args:tt)+) => {
+        if let Some(logger) = &$r.logger {
+            logger.log(Level::Error, format_args!($($args)+))
+        }
+    };
+);
+
+/// What happens to events that arrive while a command is already running or
+/// waiting for its turn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// Ignore them.
+    Drop,
+    /// Run once more afterwards, for all of them together.
+    Coalesce,
+    /// Run once more afterwards, for the first of them.
+    QueueOne,
+}
+
+/// What `Runner::trigger` does once more events wait to be run for than
+/// allowed, see `RunnerBuilder::max_queued`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// Wait until the command caught up. This holds up the caller, e.g. the
+    /// watcher's event loop, so further changes wait in the kernel's queue,
+    /// which may overflow.
+    Block,
+    /// Drop the oldest waiting events. Dropping all events of a run cancels
+    /// it.
+    DropOldest,
+    /// Merge the waiting events for the same path into the latest one, then
+    /// drop the oldest if there still are too many.
+    CoalescePerPath,
+}
+
+/// How a `Runner` treats a running command when a new change arrives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionMode {
+    /// Let it finish, and apply the `QueuePolicy` to the change.
+    Sequential,
+    /// Terminate it and start a fresh run for the change, like a supervisor
+    /// restarting a server. The `QueuePolicy` does not apply.
+    RestartOnChange,
+    /// Run the command for changes to different paths concurrently, on up to
+    /// this many threads. Changes to the same path are never run at the same
+    /// time; the `QueuePolicy` applies to each path on its own. The minimum
+    /// interval of the `Throttle` does not apply. The environment variables
+    /// of `EnvMode::Event` are always set, so each run can tell which path it
+    /// is for.
+    Parallel(usize),
+}
+
+/// Which environment variables describing the triggering events are set for
+/// the command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvMode {
+    /// None.
+    Off,
+    /// `HOTRELOAD_PATH`, `HOTRELOAD_KIND` and `HOTRELOAD_ROOT`, describing the
+    /// first event of the run.
+    Event,
+    /// Like `Event`, plus `HOTRELOAD_PATHS`, the newline-separated list of the
+    /// distinct paths of the events the run is for. Only with
+    /// `QueuePolicy::Coalesce` are these all paths that changed since the
+    /// previous run, `Drop` and `QueueOne` discard the events of the rest.
+    Batch,
+}
+
+/// What the command of a `Runner` is doing, or did last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// It has not run yet.
+    Idle,
+    Running,
+    Exited(ExitStatus),
+    /// It could not be started.
+    Failed,
+    /// It was terminated for running longer than its timeout.
+    TimedOut,
+}
+
+impl Status {
+    /// Whether the command exited unsuccessfully or could not be started.
+    pub fn is_failure(&self) -> bool {
+        match self {
+            Status::Exited(status) => !status.success(),
+            Status::Failed | Status::TimedOut => true,
+            Status::Idle | Status::Running => false,
+        }
+    }
+}
+
+/// How a `Runner` with a `BatchTrigger` passes the changed paths to its
+/// command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchPaths {
+    /// As arguments, after the command's own. Too many for one command line
+    /// are split across several runs like `xargs` does, up to the first run
+    /// that fails.
+    Args,
+    /// On stdin, one per line.
+    Stdin,
+}
+
+/// Runs the command only once no change arrived for `quiet`, for all the
+/// changes since the previous run, with the distinct changed paths passed to
+/// the first step of the pipeline. See `RunnerBuilder::batch`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchTrigger {
+    pub quiet: Duration,
+    pub paths: BatchPaths,
+}
+
+/// What a `Runner` does when its command fails.
+#[derive(Clone)]
+pub struct ExitPolicy {
+    /// How often a failed command is run again for the same events, unless
+    /// another change arrives first.
+    pub retries: usize,
+    /// The time before the first retry, doubled for each further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Run once the command failed and will not be retried, with
+    /// `HOTRELOAD_EXIT_CODE` set if it exited with a code.
+    pub on_failure: Option<Executor>,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> ExitPolicy {
+        ExitPolicy {
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            on_failure: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Step {
+    executor: Executor,
+    timeout: Option<Duration>,
+}
+
+/// The commands a `Runner` runs one after the other, each only if the
+/// previous one succeeded, e.g. a check, a build and a restart.
+///
+/// A run of the pipeline counts as one execution, which fails with the first
+/// step that fails. In `RestartOnChange` mode, a change arriving meanwhile
+/// terminates the running step and cancels the rest of the pipeline.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline { Pipeline::default() }
+
+    pub fn step(mut self, executor: Executor) -> Pipeline {
+        self.steps.push(Step { executor, timeout: None });
+        self
+    }
+
+    /// Adds a step that is terminated, failing the pipeline, if it runs
+    /// longer than `timeout`.
+    pub fn step_with_timeout(mut self, executor: Executor, timeout: Duration) -> Pipeline {
+        self.steps.push(Step { executor, timeout: Some(timeout) });
+        self
+    }
+
+    pub fn len(&self) -> usize { self.steps.len() }
+
+    pub fn is_empty(&self) -> bool { self.steps.is_empty() }
+}
+
+impl From<Executor> for Pipeline {
+    fn from(executor: Executor) -> Pipeline { Pipeline::new().step(executor) }
+}
+
+/// How long a terminated command gets to exit after SIGTERM before it is killed.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+// How often a command is checked for having exited in `RestartOnChange` mode.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Throttle {
+    /// The minimum time between the start of two executions.
+    pub min_interval: Duration,
+    pub queue: QueuePolicy,
+}
+
+impl Default for Throttle {
+    fn default() -> Throttle {
+        Throttle {
+            min_interval: Duration::from_secs(0),
+            queue: QueuePolicy::QueueOne,
+        }
+    }
+}
+
+type ExitHook = Arc<dyn Fn(Status, &[WatchEvent]) + Send + Sync>;
+
+pub struct RunnerBuilder {
+    pipeline: Pipeline,
+    throttle: Throttle,
+    mode: ExecutionMode,
+    env: EnvMode,
+    outputs: Vec<Glob>,
+    suppress: Option<Duration>,
+    on_exit: Option<ExitHook>,
+    exit_policy: ExitPolicy,
+    max_queued: Option<(usize, Backpressure)>,
+    batch: Option<BatchTrigger>,
+    logger: Option<Arc<dyn WatcherLog>>,
+}
+
+impl RunnerBuilder {
+    /// Runs a single command, or a `Pipeline` of them.
+    pub fn new<P: Into<Pipeline>>(pipeline: P) -> RunnerBuilder {
+        RunnerBuilder {
+            pipeline: pipeline.into(),
+            throttle: Throttle::default(),
+            mode: ExecutionMode::Sequential,
+            env: EnvMode::Off,
+            outputs: Vec::new(),
+            suppress: None,
+            on_exit: None,
+            exit_policy: ExitPolicy::default(),
+            max_queued: None,
+            batch: None,
+            logger: None,
+        }
+    }
+
+    pub fn throttle(mut self, throttle: Throttle) -> RunnerBuilder {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn mode(mut self, mode: ExecutionMode) -> RunnerBuilder {
+        self.mode = mode;
+        self
+    }
+
+    pub fn env(mut self, env: EnvMode) -> RunnerBuilder {
+        self.env = env;
+        self
+    }
+
+    /// Declares that the command writes paths matching `pattern`, relative to
+    /// the watched root, so that changes to them do not trigger it again. Can
+    /// be called repeatedly.
+    pub fn output(mut self, pattern: &str) -> RunnerBuilder {
+        self.outputs.push(Glob::new(pattern));
+        self
+    }
+
+    /// Ignores events arriving while the command runs, or within `window`
+    /// after it exited, for paths that also changed during the previous run,
+    /// as most likely written by the command itself. Other events are queued
+    /// as usual, so a path the command writes on every run triggers it once
+    /// more at most. Changes made by anything else meanwhile to such a path
+    /// are ignored as well. Not applied in `RestartOnChange` mode, where the
+    /// command runs until the next change.
+    pub fn suppress_own_writes(mut self, window: Duration) -> RunnerBuilder {
+        self.suppress = Some(window);
+        self
+    }
+
+    pub fn exit_policy(mut self, policy: ExitPolicy) -> RunnerBuilder {
+        self.exit_policy = policy;
+        self
+    }
+
+    /// Applies `policy` once more than `capacity` events wait to be run for,
+    /// which otherwise accumulate without bound, e.g. with
+    /// `QueuePolicy::Coalesce` or in `Parallel` mode.
+    pub fn max_queued(mut self, capacity: usize, policy: Backpressure) -> RunnerBuilder {
+        self.max_queued = Some((capacity, policy));
+        self
+    }
+
+    /// Collects changes until the tree was quiet for the trigger's period,
+    /// including those arriving during a run, and then runs the command once
+    /// for all of them. The `QueuePolicy` does not apply, and in
+    /// `RestartOnChange` mode a running command is only restarted once the
+    /// next batch is complete. Not applied in `Parallel` mode.
+    pub fn batch(mut self, trigger: BatchTrigger) -> RunnerBuilder {
+        self.batch = Some(trigger);
+        self
+    }
+
+    /// Calls `hook` with the outcome and the triggering events once the
+    /// command exits, or could not be started. Not called for commands
+    /// terminated in `RestartOnChange` mode.
+    pub fn on_exit<F>(mut self, hook: F) -> RunnerBuilder
+        where F: Fn(Status, &[WatchEvent]) + Send + Sync + 'static {
+        self.on_exit = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn log<L: WatcherLog + 'static>(mut self, logger: L) -> RunnerBuilder {
+        self.logger = Some(Arc::new(logger));
+        self
+    }
+
+    #[cfg(feature = "slog")]
+    pub fn logger(self, logger: slog::Logger) -> RunnerBuilder { self.log(logger) }
+
+    /// Panics if the pipeline has no steps.
+    pub fn build(self) -> Runner {
+        assert!(!self.pipeline.is_empty(), "The pipeline has no steps");
+
+        let batch = match self.mode {
+            ExecutionMode::Parallel(_) => None,
+            _ => self.batch,
+        };
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                running: false,
+                pending: None,
+                shutdown: false,
+                queued: VecDeque::new(),
+                busy: HashMap::new(),
+                executing: 0,
+                last_exit: None,
+                last_change: None,
+                touched: HashSet::new(),
+                written: HashSet::new(),
+                groups: HashSet::new(),
+                status: Status::Idle,
+                stats: RunnerStats::default(),
+            }),
+            wake: Condvar::new(),
+            pipeline: Mutex::new(self.pipeline),
+        });
+
+        let worker = Worker {
+            throttle: self.throttle,
+            mode: self.mode,
+            env: self.env,
+            on_exit: self.on_exit,
+            exit_policy: self.exit_policy,
+            batch,
+            logger: self.logger,
+            shared: shared.clone(),
+        };
+
+        let threads = match self.mode {
+            ExecutionMode::Parallel(threads) => threads.max(1),
+            _ => 1,
+        };
+
+        let workers = (0..threads).map(|_| {
+            let worker = worker.clone();
+
+            thread::spawn(move || worker.run())
+        }).collect();
+
+        Runner {
+            queue: self.throttle.queue,
+            mode: self.mode,
+            outputs: self.outputs,
+            suppress: if self.mode == ExecutionMode::RestartOnChange { None } else { self.suppress },
+            max_queued: self.max_queued,
+            batch,
+            shared,
+            workers,
+        }
+    }
+}
+
+struct State {
+    running: bool,
+    // The events the next execution is for, if one is due.
+    pending: Option<Vec<WatchEvent>>,
+    shutdown: bool,
+    // In `Parallel` mode, the events per path waiting for a thread, in order
+    // of arrival.
+    queued: VecDeque<(PathBuf, Vec<WatchEvent>)>,
+    // In `Parallel` mode, the paths being executed for, with the events that
+    // arrived for them meanwhile.
+    busy: HashMap<PathBuf, Option<Vec<WatchEvent>>>,
+    // The number of commands running, and when one last exited.
+    executing: usize,
+    last_exit: Option<Instant>,
+    // With a `BatchTrigger`, when the latest event arrived.
+    last_change: Option<Instant>,
+    // When suppressing own writes, the paths changed during the current run
+    // and its window, and during the previous one.
+    touched: HashSet<PathBuf>,
+    written: HashSet<PathBuf>,
+    // The process groups of the commands running, for `Runner::shutdown`.
+    groups: HashSet<u32>,
+    // How the last command exited.
+    status: Status,
+    stats: RunnerStats,
+}
+
+impl State {
+    // The number of events waiting to be run for.
+    fn depth(&self) -> usize {
+        self.pending.as_ref().map_or(0, Vec::len)
+            + self.queued.iter().map(|(_, events)| events.len()).sum::<usize>()
+            + self.busy.values().flatten().map(Vec::len).sum::<usize>()
+    }
+
+    // Drops the oldest waiting event, cancelling its run if it was the last
+    // one. Returns `false` if none are waiting.
+    fn drop_oldest(&mut self) -> bool {
+        if let Some(events) = self.pending.as_mut().filter(|events| !events.is_empty()) {
+            events.remove(0);
+
+            if events.is_empty() {
+                self.pending = None;
+            }
+
+            return true;
+        }
+
+        if let Some(i) = self.queued.iter().position(|(_, events)| !events.is_empty()) {
+            self.queued[i].1.remove(0);
+
+            if self.queued[i].1.is_empty() {
+                self.queued.remove(i);
+            }
+
+            return true;
+        }
+
+        for waiting in self.busy.values_mut() {
+            if let Some(events) = waiting.as_mut().filter(|events| !events.is_empty()) {
+                events.remove(0);
+
+                if events.is_empty() {
+                    *waiting = None;
+                }
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Merges the waiting events for the same path. Returns how many were
+    // merged into others.
+    fn coalesce(&mut self) -> usize {
+        self.pending.iter_mut()
+            .chain(self.queued.iter_mut().map(|(_, events)| events))
+            .chain(self.busy.values_mut().flatten())
+            .map(coalesce_paths)
+            .sum()
+    }
+}
+
+// Replaces the events for a path with the latest one, in place of the first,
+// which stays a creation if the path was created.
+fn coalesce_paths(events: &mut Vec<WatchEvent>) -> usize {
+    let count = events.len();
+    let mut merged: Vec<WatchEvent> = Vec::with_capacity(count);
+
+    for event in events.drain(..) {
+        match merged.iter_mut().find(|merged| merged.path == event.path) {
+            Some(merged) => {
+                let created = merged.kind == EventKind::Created && event.kind == EventKind::Modified;
+
+                *merged = event;
+
+                if created {
+                    merged.kind = EventKind::Created;
+                }
+            },
+            None => merged.push(event),
+        }
+    }
+
+    *events = merged;
+
+    count - events.len()
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Waited on by idle workers and by those supervising or retrying alike,
+    // so it is always notified with `notify_all`.
+    wake: Condvar,
+    // Apart from the state, so a running pipeline does not hold the lock.
+    pipeline: Mutex<Pipeline>,
+}
+
+/// Executes a command in the background whenever it is triggered by an
+/// event, without blocking the watcher's event loop.
+pub struct Runner {
+    queue: QueuePolicy,
+    mode: ExecutionMode,
+    outputs: Vec<Glob>,
+    suppress: Option<Duration>,
+    max_queued: Option<(usize, Backpressure)>,
+    batch: Option<BatchTrigger>,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Runner {
+    pub fn builder<P: Into<Pipeline>>(pipeline: P) -> RunnerBuilder { RunnerBuilder::new(pipeline) }
+
+    pub fn trigger(&self, event: WatchEvent) {
+        if self.outputs.iter().any(|glob| glob.matches(event.relative_path())) {
+            return;
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(window) = self.suppress {
+            if state.executing > 0 || state.last_exit.is_some_and(|exit| exit.elapsed() < window) {
+                let written = state.written.contains(&event.path);
+
+                state.touched.insert(event.path.clone());
+
+                if written {
+                    return;
+                }
+            }
+        }
+
+        self.enqueue(&mut state, event);
+
+        let depth = state.depth();
+        state.stats.max_queue_depth = state.stats.max_queue_depth.max(depth);
+
+        match self.max_queued {
+            Some((capacity, policy)) if depth > capacity => self.relieve(state, capacity, policy),
+            _ => {},
+        }
+    }
+
+    fn enqueue(&self, state: &mut State, event: WatchEvent) {
+        if let ExecutionMode::Parallel(_) = self.mode {
+            return self.trigger_path(state, event);
+        }
+
+        if self.batch.is_some() {
+            state.last_change = Some(Instant::now());
+            state.pending.get_or_insert_with(Vec::new).push(event);
+            self.shared.wake.notify_all();
+
+            return;
+        }
+
+        match (&mut state.pending, self.queue) {
+            (Some(events), _) if self.mode == ExecutionMode::RestartOnChange => events.push(event),
+            (Some(events), QueuePolicy::Coalesce) => events.push(event),
+            (Some(_), _) => {},
+            (pending, _) if self.mode == ExecutionMode::RestartOnChange => {
+                *pending = Some(vec![event]);
+                self.shared.wake.notify_all();
+            },
+            (None, QueuePolicy::Drop) if state.running => {},
+            (pending, _) => {
+                *pending = Some(vec![event]);
+                self.shared.wake.notify_all();
+            },
+        }
+    }
+
+    /// Runs the command once more, as if triggered by a change, but without
+    /// any events. Neither outputs nor the queue policy apply.
+    pub fn force(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let ExecutionMode::Parallel(_) = self.mode {
+            state.queued.push_back((PathBuf::new(), Vec::new()));
+            self.shared.wake.notify_all();
+
+            return;
+        }
+
+        if state.pending.is_none() {
+            state.pending = Some(Vec::new());
+            self.shared.wake.notify_all();
+        }
+    }
+
+    /// Replaces the pipeline for the executions started from now on. One
+    /// already running is not interrupted. Panics if it has no steps.
+    pub fn set_pipeline<P: Into<Pipeline>>(&self, pipeline: P) {
+        let pipeline = pipeline.into();
+
+        assert!(!pipeline.is_empty(), "The pipeline has no steps");
+
+        *self.shared.pipeline.lock().unwrap() = pipeline;
+    }
+
+    /// Stops the runner like dropping it, but terminates the commands
+    /// running in any mode: their process groups get SIGTERM, and SIGKILL
+    /// after `grace`. As commands run in their own process group, the
+    /// terminal's Ctrl-C does not reach them, so call this before exiting.
+    pub fn shutdown(&self, grace: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.shutdown = true;
+        self.shared.wake.notify_all();
+
+        let groups: Vec<u32> = state.groups.iter().copied().collect();
+
+        for &group in &groups {
+            unsafe { libc::kill(-(group as libc::pid_t), libc::SIGTERM) };
+        }
+
+        let deadline = Instant::now() + grace;
+
+        while !state.groups.is_empty() {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(wait) => state = self.shared.wake.wait_timeout(state, wait.min(POLL_INTERVAL)).unwrap().0,
+                None => break,
+            }
+        }
+
+        // Also ends the processes that outlived their command.
+        for &group in groups.iter().chain(&state.groups) {
+            unsafe { libc::kill(-(group as libc::pid_t), libc::SIGKILL) };
+        }
+    }
+
+    pub fn stats(&self) -> RunnerStats {
+        let state = self.shared.state.lock().unwrap();
+
+        RunnerStats {
+            queue_depth: state.depth(),
+            ..state.stats.clone()
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.shared.state.lock().unwrap();
+
+        if state.executing > 0 { Status::Running } else { state.status }
+    }
+
+    // Applies the backpressure policy to the events waiting beyond `capacity`.
+    fn relieve(&self, mut state: MutexGuard<State>, capacity: usize, policy: Backpressure) {
+        if policy == Backpressure::Block {
+            state.stats.blocked += 1;
+
+            while !state.shutdown && state.depth() > capacity {
+                state = self.shared.wake.wait(state).unwrap();
+            }
+
+            return;
+        }
+
+        if policy == Backpressure::CoalescePerPath {
+            state.stats.coalesced += state.coalesce() as u64;
+        }
+
+        while state.depth() > capacity && state.drop_oldest() {
+            state.stats.dropped += 1;
+        }
+    }
+
+    // Applies the queue policy to the events of the event's path only.
+    fn trigger_path(&self, state: &mut State, event: WatchEvent) {
+        if let Some((_, events)) = state.queued.iter_mut().find(|(path, _)| *path == event.path) {
+            if self.queue == QueuePolicy::Coalesce {
+                events.push(event);
+            }
+
+            return;
+        }
+
+        match (state.busy.get_mut(&event.path), self.queue) {
+            (Some(Some(events)), QueuePolicy::Coalesce) => events.push(event),
+            (Some(Some(_)), _) | (Some(None), QueuePolicy::Drop) => {},
+            (Some(waiting), _) => {
+                *waiting = Some(vec![event]);
+                // Ends a retry's backoff early.
+                self.shared.wake.notify_all();
+            },
+            (None, _) => {
+                state.queued.push_back((event.path.clone(), vec![event]));
+                self.shared.wake.notify_all();
+            },
+        }
+    }
+}
+
+impl Drop for Runner {
+    // Waits for a running command to finish, or terminates it in
+    // `RestartOnChange` mode, but skips any pending execution.
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+
+            state.shutdown = true;
+            self.shared.wake.notify_all();
+        }
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Worker {
+    throttle: Throttle,
+    mode: ExecutionMode,
+    env: EnvMode,
+    on_exit: Option<ExitHook>,
+    exit_policy: ExitPolicy,
+    batch: Option<BatchTrigger>,
+    logger: Option<Arc<dyn WatcherLog>>,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    fn run(self) {
+        if let ExecutionMode::Parallel(_) = self.mode {
+            return self.run_paths();
+        }
+
+        let mut last_start: Option<Instant> = None;
+
+        while let Some(events) = self.next_run(last_start) {
+            last_start = Some(Instant::now());
+
+            self.run_with_retries(&events, None);
+            self.shared.state.lock().unwrap().running = false;
+        }
+    }
+
+    fn run_paths(self) {
+        while let Some((path, events)) = self.next_path() {
+            self.run_with_retries(&events, Some(&path));
+
+            let mut state = self.shared.state.lock().unwrap();
+
+            // Events that arrived during the execution go to the back of the queue.
+            if let Some(Some(events)) = state.busy.remove(&path) {
+                state.queued.push_back((path, events));
+                self.shared.wake.notify_all();
+            }
+        }
+    }
+
+    // Runs the command for the events, and again as the exit policy asks for.
+    // In `Parallel` mode, `path` is the one the events are for.
+    fn run_with_retries(&self, events: &[WatchEvent], path: Option<&Path>) {
+        let mut attempt = 0;
+
+        while let Some(status) = self.execute(events) {
+            if !status.is_failure() {
+                return;
+            }
+
+            if attempt == self.exit_policy.retries {
+                return self.give_up(status, events);
+            }
+
+            if !self.wait_to_retry(attempt, path) {
+                return;
+            }
+
+            attempt += 1;
+        }
+    }
+
+    // Runs the pipeline once and records the outcome. Returns `None` if it
+    // was terminated to be restarted.
+    fn execute(&self, events: &[WatchEvent]) -> Option<Status> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+
+            // Overlapping runs in `Parallel` mode count as one.
+            if state.executing == 0 {
+                state.written = mem::take(&mut state.touched);
+            }
+
+            state.executing += 1;
+        }
+
+        let env = self.env_for(events);
+        let pipeline = self.shared.pipeline.lock().unwrap().clone();
+        let mut status = None;
+
+        for (i, step) in pipeline.steps.iter().enumerate() {
+            let result = match (&self.batch, i) {
+                (Some(batch), 0) => self.run_batch(step, &env, batch.paths, events),
+                _ => step.executor.spawn_with_env(&env).and_then(|execution| self.supervise(execution, step.timeout)),
+            };
+
+            status = match result {
+                Ok(status) => status,
+                Err(e) => {
+                    runner_error!(self, "Failed to execute command: {}", e);
+                    Some(Status::Failed)
+                },
+            };
+
+            if status.map_or(true, |status| status.is_failure()) {
+                if status.is_some() && pipeline.len() > 1 {
+                    runner_info!(self, "Step {} of {} failed, skipping the rest", i + 1, pipeline.len());
+                }
+
+                break;
+            }
+        }
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+
+            if let Some(status) = status {
+                state.status = status;
+                state.stats.record(status);
+            }
+
+            state.executing -= 1;
+            state.last_exit = Some(Instant::now());
+        }
+
+        if let (Some(status), Some(on_exit)) = (status, &self.on_exit) {
+            on_exit(status, events);
+        }
+
+        status
+    }
+
+    // Waits out the backoff before retry `attempt`. Returns `false` if another
+    // change arrived meanwhile, which is run for instead, or the runner was
+    // dropped.
+    fn wait_to_retry(&self, attempt: usize, path: Option<&Path>) -> bool {
+        let policy = &self.exit_policy;
+        let backoff = policy.backoff.checked_mul(1 << attempt.min(31)).unwrap_or(policy.max_backoff);
+        let deadline = Instant::now() + backoff.min(policy.max_backoff);
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            let superseded = match path {
+                Some(path) => state.busy.get(path).is_some_and(Option::is_some),
+                None => state.pending.is_some(),
+            };
+
+            if state.shutdown || superseded {
+                return false;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining == Duration::from_secs(0) {
+                state.stats.retries += 1;
+                runner_info!(self, "Retrying failed command ({} of {})", attempt + 1, policy.retries);
+
+                return true;
+            }
+
+            state = self.shared.wake.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    // Runs the failure command, once the command will not be retried.
+    fn give_up(&self, status: Status, events: &[WatchEvent]) {
+        let on_failure = match &self.exit_policy.on_failure {
+            Some(on_failure) => on_failure,
+            None => return,
+        };
+
+        let mut env = self.env_for(events);
+
+        if let Some(code) = match status { Status::Exited(status) => status.code(), _ => None } {
+            env.push(("HOTRELOAD_EXIT_CODE", OsString::from(code.to_string())));
+        }
+
+        if let Err(e) = on_failure.spawn_with_env(&env).and_then(Execution::wait) {
+            runner_error!(self, "Failed to execute failure command: {}", e);
+        }
+    }
+
+    // Blocks until a path not being executed for has events. Returns `None`
+    // once the runner is dropped.
+    fn next_path(&self) -> Option<(PathBuf, Vec<WatchEvent>)> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if state.shutdown {
+                return None;
+            }
+
+            if let Some((path, events)) = state.queued.pop_front() {
+                state.busy.insert(path.clone(), None);
+                // Makes room for a trigger waiting with `Backpressure::Block`.
+                self.shared.wake.notify_all();
+
+                return Some((path, events));
+            }
+
+            state = self.shared.wake.wait(state).unwrap();
+        }
+    }
+
+    fn env_for(&self, events: &[WatchEvent]) -> Vec<(&'static str, OsString)> {
+        let mut env = Vec::new();
+        let per_path = matches!(self.mode, ExecutionMode::Parallel(_));
+
+        if let (Some(event), true) = (events.first(), self.env != EnvMode::Off || per_path) {
+            env.push(("HOTRELOAD_PATH", event.path.clone().into_os_string()));
+            env.push(("HOTRELOAD_KIND", OsString::from(event.kind.name())));
+            env.push(("HOTRELOAD_ROOT", event.root.clone().into_os_string()));
+        }
+
+        if self.env == EnvMode::Batch {
+            let mut paths = OsString::new();
+
+            for path in distinct_paths(events) {
+                if !paths.is_empty() {
+                    paths.push("\n");
+                }
+
+                paths.push(path);
+            }
+
+            env.push(("HOTRELOAD_PATHS", paths));
+        }
+
+        env
+    }
+
+    // Waits for the command to exit, terminating it once it exceeds the
+    // timeout, or in `RestartOnChange` mode early if another change arrives
+    // or the runner is dropped. Returns `None` if it was terminated early,
+    // also by `Runner::shutdown`.
+    fn supervise(&self, execution: Execution, timeout: Option<Duration>) -> Result<Option<Status>, Error> {
+        let group = execution.id();
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+
+            // Started while shutting down, so missed by `Runner::shutdown`.
+            if state.shutdown {
+                drop(state);
+                execution.terminate(GRACE_PERIOD)?;
+
+                return Ok(None);
+            }
+
+            state.groups.insert(group);
+        }
+
+        let result = self.wait_for(execution, timeout);
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.groups.remove(&group);
+        self.shared.wake.notify_all();
+
+        match result {
+            Ok(_) if state.shutdown => Ok(None),
+            result => result,
+        }
+    }
+
+    fn wait_for(&self, mut execution: Execution, timeout: Option<Duration>) -> Result<Option<Status>, Error> {
+        let restart = self.mode == ExecutionMode::RestartOnChange;
+
+        if !restart && timeout.is_none() {
+            return execution.wait().map(|status| Some(Status::Exited(status)));
+        }
+
+        let started = Instant::now();
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if restart && ((state.pending.is_some() && self.is_quiet(&state)) || state.shutdown) {
+                if state.shutdown {
+                    runner_info!(self, "Stopping command");
+                } else {
+                    state.stats.restarts += 1;
+                    runner_info!(self, "Change detected, restarting command");
+                }
+
+                drop(state);
+                execution.terminate(GRACE_PERIOD)?;
+
+                return Ok(None);
+            }
+
+            if let Some(status) = execution.try_wait()? {
+                return Ok(Some(Status::Exited(status)));
+            }
+
+            if let Some(timeout) = timeout.filter(|&timeout| started.elapsed() >= timeout) {
+                runner_error!(self, "Command timed out after {:?}, terminating it", timeout);
+
+                drop(state);
+                execution.terminate(GRACE_PERIOD)?;
+
+                return Ok(Some(Status::TimedOut));
+            }
+
+            state = self.shared.wake.wait_timeout(state, POLL_INTERVAL).unwrap().0;
+        }
+    }
+
+    // Blocks until an execution is due and the minimum interval has passed.
+    // Returns `None` once the runner is dropped.
+    fn next_run(&self, last_start: Option<Instant>) -> Option<Vec<WatchEvent>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if state.shutdown {
+                return None;
+            }
+
+            if state.pending.is_some() {
+                let interval = last_start.map(|last| last + self.throttle.min_interval);
+                let due = interval.into_iter().chain(self.quiet_until(&state)).max();
+
+                match due.and_then(|due| due.checked_duration_since(Instant::now())) {
+                    Some(wait) if wait > Duration::from_secs(0) => {
+                        state = self.shared.wake.wait_timeout(state, wait).unwrap().0;
+                    },
+                    _ => {
+                        state.running = true;
+                        // Makes room for a trigger waiting with `Backpressure::Block`.
+                        self.shared.wake.notify_all();
+
+                        return state.pending.take();
+                    },
+                }
+            } else {
+                state = self.shared.wake.wait(state).unwrap();
+            }
+        }
+    }
+
+    // Runs the first step of a batch, passing it the changed paths. Too many
+    // for one command line are passed to several runs in turn, up to the
+    // first that does not succeed.
+    fn run_batch(&self, step: &Step, env: &[(&str, OsString)], paths: BatchPaths,
+                 events: &[WatchEvent]) -> Result<Option<Status>, Error> {
+        let changed = distinct_paths(events);
+
+        if paths == BatchPaths::Stdin {
+            let mut input = Vec::new();
+
+            for path in changed {
+                input.extend_from_slice(path.as_os_str().as_bytes());
+                input.push(b'\n');
+            }
+
+            return self.supervise(step.executor.spawn_with(env, &[] as &[&Path], Some(input))?, step.timeout);
+        }
+
+        let chunks = arg_chunks(&changed, step.executor.args_budget(env));
+        let mut status = None;
+
+        if chunks.len() > 1 {
+            runner_info!(self, "Passing {} paths to {} runs", changed.len(), chunks.len());
+        }
+
+        for chunk in chunks {
+            status = self.supervise(step.executor.spawn_with(env, chunk, None)?, step.timeout)?;
+
+            if status.map_or(true, |status| status.is_failure()) {
+                break;
+            }
+        }
+
+        Ok(status)
+    }
+
+    // With a `BatchTrigger`, when the current batch is complete.
+    fn quiet_until(&self, state: &State) -> Option<Instant> {
+        let batch = self.batch?;
+
+        state.last_change.map(|change| change + batch.quiet)
+    }
+
+    fn is_quiet(&self, state: &State) -> bool { self.quiet_until(state).map_or(true, |until| until <= Instant::now()) }
+}
+
+// The paths of the events, without repetitions, in order of their first event.
+fn distinct_paths(events: &[WatchEvent]) -> Vec<&Path> {
+    let mut seen = HashSet::new();
+
+    events.iter().map(|event| event.path.as_path()).filter(|path| seen.insert(*path)).collect()
+}
+
+// Splits `paths` into as few chunks as possible of at most `budget` bytes
+// of arguments, with at least one path each, unless there are none at all.
+fn arg_chunks<'a>(paths: &'a [&'a Path], budget: usize) -> Vec<&'a [&'a Path]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+
+    for (i, path) in paths.iter().enumerate() {
+        let size = arg_size(path.as_os_str());
+
+        if i > start && used + size > budget {
+            chunks.push(&paths[start..i]);
+            start = i;
+            used = 0;
+        }
+
+        used += size;
+    }
+
+    chunks.push(&paths[start..]);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use crate::source::EventSource;
+    use std::{env, fs, process};
+
+    // Whether `pid` runs, rather than being gone or a zombie nobody reaps.
+    fn is_alive(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| !stat.contains(") Z "))
+    }
+
+    #[test]
+    fn shutdown_terminates_the_command_and_what_it_started() {
+        let pids = env::temp_dir().join(format!("aa-shutdown-{}", process::id()));
+        let _ = fs::remove_file(&pids);
+
+        let script = format!("sleep 30 & echo $$ $! > {}; wait", pids.display());
+        let runner = Runner::builder(Executor::new(&[String::from("sh"), String::from("-c"), script])).build();
+
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("a");
+        runner.trigger(mock.next_event().unwrap());
+
+        let started = Instant::now();
+
+        while !fs::read_to_string(&pids).is_ok_and(|pids| pids.ends_with('\n')) {
+            assert!(started.elapsed() < Duration::from_secs(5), "The command did not start");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        runner.shutdown(Duration::from_millis(500));
+        drop(runner);
+
+        // Signals are delivered asynchronously, which takes a moment.
+        let stopped = Instant::now();
+
+        for pid in fs::read_to_string(&pids).unwrap().split_whitespace() {
+            while is_alive(pid) {
+                assert!(stopped.elapsed() < Duration::from_millis(200), "{} outlived the shutdown", pid);
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        let _ = fs::remove_file(&pids);
+    }
+
+    #[test]
+    fn parallel_runs_other_paths_while_one_backs_off() {
+        let log = env::temp_dir().join(format!("aa-parallel-{}", process::id()));
+        let _ = fs::remove_file(&log);
+
+        let script = format!("echo \"$HOTRELOAD_PATH\" >> {}; case \"$HOTRELOAD_PATH\" in */a) exit 1;; esac",
+                             log.display());
+        let runner = Runner::builder(Executor::new(&[String::from("sh"), String::from("-c"), script]))
+            .mode(ExecutionMode::Parallel(2))
+            .exit_policy(ExitPolicy { retries: 3, backoff: Duration::from_secs(10), ..ExitPolicy::default() })
+            .build();
+
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("a").modify("b").modify("c");
+
+        let wait_for = |path: &str| {
+            let started = Instant::now();
+
+            while !fs::read_to_string(&log).unwrap_or_default().lines().any(|line| line == path) {
+                assert!(started.elapsed() < Duration::from_secs(2), "{} did not run", path);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        runner.trigger(mock.next_event().unwrap());
+        wait_for("/project/a");
+
+        // Both run while the failed run for a waits to be retried, c once
+        // the retrying worker has waited for longer than the idle one.
+        for path in ["/project/b", "/project/c"] {
+            runner.trigger(mock.next_event().unwrap());
+            wait_for(path);
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        runner.shutdown(Duration::from_millis(100));
+        let _ = fs::remove_file(&log);
+    }
+
+    #[test]
+    fn splits_paths_into_chunks_within_the_budget() {
+        let paths: Vec<&Path> = ["a", "bb", "ccc", "dddd"].iter().map(Path::new).collect();
+        let size = |name: &str| arg_size(name.as_ref());
+
+        let chunks = arg_chunks(&paths, size("a") + size("bb"));
+        assert_eq!(chunks, [&paths[..2], &paths[2..3], &paths[3..]]);
+
+        // A path larger than the budget still gets a chunk of its own.
+        assert_eq!(arg_chunks(&paths, 0).len(), 4);
+        assert_eq!(arg_chunks(&paths, usize::MAX), [&paths[..]]);
+        assert_eq!(arg_chunks(&[], 0), [&[] as &[&Path]]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::WatcherError;
+use crate::events::{EventKind, Origin, WatchEvent};
+use crate::source::EventSource;
+
+struct Timer {
+    name: String,
+    interval: Duration,
+    next: Instant,
+}
+
+/// An `EventSource` passing on the events of another one, with
+/// `EventKind::Timer` events on a schedule in between, so that e.g. a
+/// `Runner` rebuilding on change also regenerates a feed every 10 minutes,
+/// with the same throttling and logging.
+///
+/// Timers are checked while waiting for the source's events. Runs missed
+/// while the consumer was busy are skipped rather than caught up on. The
+/// same goes for runs missed while the source blocks, e.g. a paused
+/// `Watcher`, so at most one timer event follows resuming it. Consumers
+/// pausing on their own, like the tui, still receive timer events.
+pub struct Scheduler<S: EventSource> {
+    source: S,
+    root: PathBuf,
+    timers: Vec<Timer>,
+}
+
+impl<S: EventSource> Scheduler<S> {
+    /// Reports timer events for `root`, usually the source's own root.
+    pub fn new<P: AsRef<Path>>(source: S, root: P) -> Scheduler<S> {
+        Scheduler {
+            source,
+            root: root.as_ref().to_path_buf(),
+            timers: Vec::new(),
+        }
+    }
+
+    /// Reports a timer event named `name` every `interval`, the first one
+    /// `interval` from now. Can be called repeatedly. Panics if `interval`
+    /// is zero.
+    pub fn every(mut self, name: &str, interval: Duration) -> Scheduler<S> {
+        assert!(interval > Duration::from_secs(0), "The interval of '{}' is zero", name);
+
+        self.timers.push(Timer {
+            name: String::from(name),
+            interval,
+            next: Instant::now() + interval,
+        });
+        self
+    }
+
+    pub fn source(&self) -> &S { &self.source }
+
+    pub fn source_mut(&mut self) -> &mut S { &mut self.source }
+
+    pub fn into_source(self) -> S { self.source }
+
+    // Takes the event of the timer due the longest, if any, and schedules
+    // its next run.
+    fn due(&mut self, now: Instant) -> Option<WatchEvent> {
+        let timer = self.timers.iter_mut().filter(|timer| timer.next <= now).min_by_key(|timer| timer.next)?;
+
+        while timer.next <= now {
+            timer.next += timer.interval;
+        }
+
+        Some(WatchEvent {
+            kind: EventKind::Timer,
+            path: self.root.clone(),
+            root: self.root.clone(),
+            is_dir: true,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: Some(timer.name.clone()),
+        })
+    }
+
+    fn next_due(&self) -> Option<Instant> { self.timers.iter().map(|timer| timer.next).min() }
+}
+
+impl<S: EventSource> EventSource for Scheduler<S> {
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError> {
+        loop {
+            let now = Instant::now();
+
+            if let Some(event) = self.due(now) {
+                return Ok(event);
+            }
+
+            let next = match self.next_due() {
+                Some(next) => next,
+                None => return self.source.next_event(),
+            };
+
+            if let Some(event) = self.source.next_event_timeout(next.saturating_duration_since(now))? {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let now = Instant::now();
+
+            if let Some(event) = self.due(now) {
+                return Ok(Some(event));
+            }
+
+            let until = self.next_due().map_or(deadline, |next| next.min(deadline));
+
+            if let Some(event) = self.source.next_event_timeout(until.saturating_duration_since(now))? {
+                return Ok(Some(event));
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool { self.timers.is_empty() && self.source.is_finished() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use std::thread;
+
+    #[test]
+    fn interleaves_timers_and_skips_missed_runs() {
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("a");
+
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(mock, "/project").every("tick", Duration::from_millis(50));
+        let timeout = Duration::from_secs(1);
+
+        let event = scheduler.next_event_timeout(timeout).unwrap().unwrap();
+        assert_eq!(event.kind, EventKind::Modified);
+
+        let event = scheduler.next_event_timeout(timeout).unwrap().unwrap();
+        assert_eq!(event.kind, EventKind::Timer);
+        assert_eq!(event.timer.as_deref(), Some("tick"));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        scheduler.source_mut().modify("b");
+        assert_eq!(scheduler.next_event_timeout(timeout).unwrap().unwrap().kind, EventKind::Modified);
+
+        // Three runs are missed, only one is reported.
+        thread::sleep(Duration::from_millis(170));
+
+        assert_eq!(scheduler.next_event_timeout(Duration::from_secs(0)).unwrap().unwrap().kind, EventKind::Timer);
+        assert!(scheduler.next_event_timeout(Duration::from_secs(0)).unwrap().is_none());
+        assert!(!scheduler.is_finished());
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::events::{Origin, WatchEvent};
+use crate::runner::Runner;
+
+/// The function a script has to define.
+pub const ENTRY_POINT: &str = "decide";
+
+/// How many operations, and nested function calls, one call of `decide` may
+/// take before failing, so that a script stuck in a loop or recursion does
+/// not hang the watcher.
+pub const MAX_OPERATIONS: u64 = 1_000_000;
+pub const MAX_CALL_LEVELS: usize = 32;
+
+/// A rhai script deciding which of several named runners handles each
+/// event, so that the logic can change without recompiling, e.g.
+///
+/// ```rhai
+/// fn decide(event) {
+///     if event.relative.starts_with("src/routes/") {
+///         "restart"
+///     } else if event.relative.ends_with(".css") {
+///         ["reload", "notify"]
+///     }
+/// }
+/// ```
+///
+/// `decide` receives the event as a map with the `kind`, `path`, `root`,
+/// `relative` path, `is_dir`, `origin`, for storms `count`, and for timers
+/// the `timer`'s name. It returns
+/// the name of an action, an array of names, or nothing to ignore the event.
+/// Paths that are not UTF-8 are passed lossily.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    path: Option<PathBuf>,
+    actions: Vec<(String, Runner)>,
+}
+
+impl Script {
+    pub fn new(source: &str) -> io::Result<Script> {
+        let mut engine = Engine::new();
+
+        engine.set_max_operations(MAX_OPERATIONS).set_max_call_levels(MAX_CALL_LEVELS);
+
+        let ast = compile(&engine, source)?;
+
+        Ok(Script {
+            engine,
+            ast,
+            path: None,
+            actions: Vec::new(),
+        })
+    }
+
+    /// Compiles the script at `path`, which `reload` reads again.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Script> {
+        let path = path.as_ref();
+        let mut script = Script::new(&fs::read_to_string(path)?)?;
+
+        script.path = Some(path.to_path_buf());
+
+        Ok(script)
+    }
+
+    /// Runs `runner` when the script decides on `name`. Can be called
+    /// repeatedly.
+    pub fn action(mut self, name: &str, runner: Runner) -> Script {
+        self.actions.push((String::from(name), runner));
+        self
+    }
+
+    pub fn runner(&self, name: &str) -> Option<&Runner> {
+        self.actions.iter().find(|(action, _)| action == name).map(|(_, runner)| runner)
+    }
+
+    /// The runners of all actions, e.g. to shut them down.
+    pub fn runners(&self) -> impl Iterator<Item = &Runner> { self.actions.iter().map(|(_, runner)| runner) }
+
+    pub fn path(&self) -> Option<&Path> { self.path.as_deref() }
+
+    /// Compiles the script's file again. If that fails, the current version
+    /// is kept.
+    pub fn reload(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.ast = compile(&self.engine, &fs::read_to_string(path)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of the actions the script decided on for the event, which
+    /// need not be registered.
+    pub fn decide(&self, event: &WatchEvent) -> io::Result<Vec<String>> {
+        let decision: Dynamic = self.engine
+                                    .call_fn(&mut Scope::new(), &self.ast, ENTRY_POINT, (to_map(event),))
+                                    .map_err(invalid)?;
+
+        if decision.is_unit() {
+            Ok(Vec::new())
+        } else if decision.is_string() {
+            Ok(vec![decision.into_string().unwrap()])
+        } else if decision.is_array() {
+            decision.into_typed_array::<String>()
+                    .map_err(|_| invalid("decide returned an array of something other than names"))
+        } else {
+            Err(invalid(format!("decide returned a {} instead of a name", decision.type_name())))
+        }
+    }
+
+    /// Triggers the runners the script decided on for the event. An unknown
+    /// name is an error, after triggering the known ones.
+    pub fn handle(&self, event: &WatchEvent) -> io::Result<()> {
+        let mut unknown = Vec::new();
+
+        for name in self.decide(event)? {
+            match self.runner(&name) {
+                Some(runner) => runner.trigger(event.clone()),
+                None => unknown.push(name),
+            }
+        }
+
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid(format!("Unknown action(s): {}", unknown.join(", "))))
+        }
+    }
+}
+
+fn to_map(event: &WatchEvent) -> Map {
+    let mut map = Map::new();
+    let string = |path: &Path| Dynamic::from(path.to_string_lossy().into_owned());
+
+    map.insert("kind".into(), Dynamic::from(event.kind.name().to_string()));
+    map.insert("path".into(), string(&event.path));
+    map.insert("root".into(), string(&event.root));
+    map.insert("relative".into(), string(event.relative_path()));
+    map.insert("is_dir".into(), Dynamic::from(event.is_dir));
+    map.insert("origin".into(), Dynamic::from(String::from(match event.origin {
+        Origin::External => "external",
+        Origin::Own => "own",
+    })));
+
+    if let Some(storm) = &event.storm {
+        map.insert("count".into(), Dynamic::from(storm.count as i64));
+    }
+
+    if let Some(timer) = &event.timer {
+        map.insert("timer".into(), Dynamic::from(timer.clone()));
+    }
+
+    map
+}
+
+fn compile(engine: &Engine, source: &str) -> io::Result<AST> {
+    let ast = engine.compile(source).map_err(invalid)?;
+
+    if !ast.iter_functions().any(|f| f.name == ENTRY_POINT && f.params.len() == 1) {
+        return Err(invalid(format!("The script does not define {}(event)", ENTRY_POINT)));
+    }
+
+    Ok(ast)
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::dispatcher::Control;
+use crate::error::WatcherError;
+use crate::events::{EventKind, WatchEvent};
+use crate::runner::Runner;
+use crate::watchers::Watcher;
+
+type Handler = Box<dyn FnMut(&WatchEvent) -> Control>;
+
+/// Owns several watchers, each with its own handler, and reads all of them
+/// on the thread calling `run` by polling their file descriptors.
+///
+/// Watchers may overlap, e.g. for `./src` and `.`, also through symlinks:
+/// a change reported by several of them is only passed to the handler of the
+/// one added first, by its canonical path. Each watcher still has its own
+/// inotify watches, and events read far enough apart, e.g. while a handler
+/// runs, may still be passed twice.
+///
+/// A paused watcher blocks the whole set until it is resumed.
+#[derive(Default)]
+pub struct WatcherSet {
+    // With the canonical path of each watcher's root.
+    watchers: Vec<(Watcher, Handler, PathBuf)>,
+}
+
+impl WatcherSet {
+    pub fn new() -> WatcherSet { WatcherSet::default() }
+
+    pub fn add<F>(mut self, watcher: Watcher, handler: F) -> WatcherSet
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        let root = fs::canonicalize(watcher.root()).unwrap_or_else(|_| watcher.root().to_path_buf());
+
+        self.watchers.push((watcher, Box::new(handler), root));
+        self
+    }
+
+    /// Triggers `runner` for every event of `watcher`.
+    pub fn add_runner(self, watcher: Watcher, runner: Runner) -> WatcherSet {
+        self.add(watcher, move |event| {
+            runner.trigger(event.clone());
+
+            Control::Continue
+        })
+    }
+
+    pub fn len(&self) -> usize { self.watchers.len() }
+
+    pub fn is_empty(&self) -> bool { self.watchers.is_empty() }
+
+    /// Blocks, passing every event to the handler of the watcher it came
+    /// from, until a handler returns `Control::Stop` or a watcher fails.
+    /// Returns immediately if the set is empty.
+    pub fn run(&mut self) -> Result<(), WatcherError> {
+        // The changes passed on, with the watcher that did, in this and the
+        // previous round, as another watcher may only be read in the next.
+        let mut previous: HashMap<(EventKind, PathBuf), usize> = HashMap::new();
+
+        while !self.watchers.is_empty() {
+            let mut current = HashMap::new();
+
+            for (i, (watcher, handler, root)) in self.watchers.iter_mut().enumerate() {
+                while let Some(event) = watcher.next_event_timeout(Duration::from_secs(0))? {
+                    let change = (event.kind, root.join(event.relative_path()));
+
+                    if current.get(&change).or_else(|| previous.get(&change)).is_some_and(|first| *first != i) {
+                        continue;
+                    }
+
+                    current.insert(change, i);
+
+                    if handler(&event) == Control::Stop {
+                        return Ok(());
+                    }
+                }
+            }
+
+            previous = current;
+            self.wait()?;
+        }
+
+        Ok(())
+    }
+
+    // Blocks until any watcher has events, or has to be read again anyway,
+    // see `Watcher::next_timeout`.
+    fn wait(&self) -> Result<(), WatcherError> {
+        let mut fds: Vec<libc::pollfd> = self.watchers.iter()
+            .map(|(watcher, _, _)| libc::pollfd {
+                fd: watcher.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        // Rounded up, so the deadline has passed when poll returns.
+        let timeout = self.watchers.iter()
+                                   .filter_map(|(watcher, _, _)| watcher.next_timeout())
+                                   .min()
+                                   .map_or(-1, |t| t.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int);
+
+        match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+            -1 => match io::Error::last_os_error() {
+                ref e if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                e => Err(WatcherError::Io(e)),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchers::WatcherBuilder;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::{env, process, thread};
+
+    #[test]
+    fn passes_on_stabilized_events_without_further_activity() {
+        let dir = env::temp_dir().join(format!("aa-set-stabilize-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let (ready_tx, ready) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let root = dir.clone();
+
+        thread::spawn(move || {
+            let watcher = WatcherBuilder::new(&root).stabilize(Duration::from_millis(100)).build().unwrap();
+            let mut set = WatcherSet::new().add(watcher, move |event| {
+                let _ = events_tx.send(event.path.clone());
+
+                Control::Stop
+            });
+
+            ready_tx.send(()).unwrap();
+            set.run().unwrap();
+        });
+
+        ready.recv().unwrap();
+
+        // Kept open, so only the stabilizer's timeout can release the event.
+        let mut file = File::create(dir.join("held")).unwrap();
+        file.write_all(b"written").unwrap();
+
+        assert_eq!(events.recv_timeout(Duration::from_secs(2)).unwrap(), dir.join("held"));
+
+        drop(file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{self, Stdout, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use crate::events::WatchEvent;
+
+/// Writes events as newline-delimited JSON, one object per line, so they
+/// can be consumed by tools not written in Rust.
+pub struct JsonSink<W: Write> {
+    writer: W,
+}
+
+impl JsonSink<Stdout> {
+    pub fn stdout() -> JsonSink<Stdout> { JsonSink::new(io::stdout()) }
+}
+
+impl JsonSink<UnixStream> {
+    pub fn unix<P: AsRef<Path>>(path: P) -> io::Result<JsonSink<UnixStream>> {
+        Ok(JsonSink::new(UnixStream::connect(path)?))
+    }
+}
+
+impl JsonSink<TcpStream> {
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> io::Result<JsonSink<TcpStream>> {
+        Ok(JsonSink::new(TcpStream::connect(addr)?))
+    }
+}
+
+impl JsonSink<Listener> {
+    /// Serves events to every client connecting to the listening socket `fd`,
+    /// e.g. one passed by systemd socket activation.
+    pub fn listen_fd(fd: RawFd) -> io::Result<JsonSink<Listener>> {
+        Ok(JsonSink::new(Listener::from_raw_fd(fd)?))
+    }
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W) -> JsonSink<W> {
+        JsonSink {
+            writer,
+        }
+    }
+
+    pub fn send(&mut self, event: &WatchEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+enum Socket {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// How much a `Listener` client may fall behind before it is dropped.
+pub const MAX_BACKLOG: usize = 1 << 20;
+
+struct Client {
+    stream: Box<dyn Write + Send>,
+    // Written, but not yet sent.
+    backlog: Vec<u8>,
+}
+
+/// Writes to all clients connected to a listening socket, without blocking.
+/// Clients are accepted when flushing, so they only receive whole lines, and
+/// dropped once writing to them fails or more than `MAX_BACKLOG` bytes wait
+/// to be sent to them.
+pub struct Listener {
+    socket: Socket,
+    clients: Vec<Client>,
+}
+
+impl Listener {
+    /// Takes ownership of the listening socket `fd`, which may be a Unix or a
+    /// TCP socket.
+    pub fn from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = match addr.ss_family as libc::c_int {
+            libc::AF_UNIX => Socket::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+            _ => Socket::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+        };
+
+        match &socket {
+            Socket::Unix(listener) => listener.set_nonblocking(true)?,
+            Socket::Tcp(listener) => listener.set_nonblocking(true)?,
+        }
+
+        Ok(Listener {
+            socket,
+            clients: Vec::new(),
+        })
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            // Accepted sockets do not inherit the listener's non-blocking mode on Linux.
+            let accepted = match &self.socket {
+                Socket::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(true)?;
+
+                    Ok(Box::new(stream) as Box<dyn Write + Send>)
+                }),
+                Socket::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(true)?;
+
+                    Ok(Box::new(stream) as Box<dyn Write + Send>)
+                }),
+            };
+
+            match accepted {
+                Ok(stream) => self.clients.push(Client {
+                    stream,
+                    backlog: Vec::new(),
+                }),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Client {
+    // Sends as much of the backlog as the socket takes. Returns `false` if the
+    // client is gone.
+    fn send(&mut self) -> bool {
+        while !self.backlog.is_empty() {
+            match self.stream.write(&self.backlog) {
+                Ok(0) => return false,
+                Ok(written) => { self.backlog.drain(..written); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return false,
+            }
+        }
+
+        true
+    }
+}
+
+impl Write for Listener {
+    // Only queued, so each line is sent once complete.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.clients.retain_mut(|client| {
+            client.backlog.extend_from_slice(buf);
+            client.backlog.len() <= MAX_BACKLOG
+        });
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.clients.retain_mut(Client::send);
+
+        self.accept()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventKind, Origin};
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::io::IntoRawFd;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn event(name: &str) -> WatchEvent {
+        WatchEvent {
+            kind: EventKind::Modified,
+            path: PathBuf::from("/project").join(name),
+            root: PathBuf::from("/project"),
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let mut sink = JsonSink::new(Vec::new());
+        sink.send(&event("a")).unwrap();
+        sink.send(&event("b")).unwrap();
+
+        let lines: Vec<WatchEvent> = sink.writer.split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        assert_eq!(lines, [event("a"), event("b")]);
+    }
+
+    #[test]
+    fn listener_sends_whole_lines_to_clients_accepted_when_flushing() {
+        let path = env::temp_dir().join(format!("aa-sink-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let fd = UnixListener::bind(&path).unwrap().into_raw_fd();
+
+        let mut sink = JsonSink::listen_fd(fd).unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+
+        // Sent before the client was accepted, so it never sees it.
+        sink.send(&event("a")).unwrap();
+        sink.send(&event("b")).unwrap();
+
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+
+        assert_eq!(serde_json::from_str::<WatchEvent>(&line).unwrap(), event("b"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::events::{EventKind, Origin, WatchEvent};
+
+// The first line of every state file, to refuse files in another format.
+const HEADER: &str = "aa-snapshot 1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The size and mtime of every watched path at one point in time, used to
+/// detect changes that happened while no watcher was running.
+///
+/// Saved as text, one path per line. Backslashes, tabs, newlines and bytes
+/// that are not valid UTF-8 are escaped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot { Snapshot::default() }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let mut snapshot = Snapshot::new();
+
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a snapshot file"));
+        }
+
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(4, '\t');
+
+            let entry = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(len), Some(modified), Some(path)) => {
+                    (path, Entry {
+                        is_dir: kind == "d",
+                        len: len.parse().map_err(invalid)?,
+                        modified: parse_time(modified)?,
+                    })
+                },
+                _ => return Err(invalid(line)),
+            };
+
+            snapshot.entries.insert(unescape_path(entry.0)?, entry.1);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Writes the snapshot to a temporary file first, so an interrupted save
+    /// does not leave a truncated one behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&temp)?);
+
+        writeln!(writer, "{}", HEADER)?;
+
+        for (path, entry) in &self.entries {
+            let modified = entry.modified
+                                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                                .map(|m| format!("{}.{:09}", m.as_secs(), m.subsec_nanos()))
+                                .unwrap_or_else(|| String::from("-"));
+
+            writeln!(writer, "{}\t{}\t{}\t{}", if entry.is_dir { "d" } else { "f" },
+                     entry.len, modified, escape_path(path))?;
+        }
+
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        fs::rename(&temp, path)
+    }
+
+    /// Records the path's current metadata.
+    pub fn insert(&mut self, path: &Path, metadata: &fs::Metadata) {
+        self.entries.insert(path.to_path_buf(), Entry {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// The events turning this snapshot into `newer`, sorted by path. A
+    /// directory is never reported as modified, since its mtime changes with
+    /// every file created or deleted in it.
+    pub fn diff(&self, newer: &Snapshot, root: &Path) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+
+        let event = |kind, path: &Path, entry: &Entry| WatchEvent {
+            kind,
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+            is_dir: entry.is_dir,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        };
+
+        for (path, old) in &self.entries {
+            match newer.entries.get(path) {
+                None => events.push(event(EventKind::Deleted, path, old)),
+                // Replaced by a directory or the other way round.
+                Some(new) if new.is_dir != old.is_dir => {
+                    events.push(event(EventKind::Deleted, path, old));
+                    events.push(event(EventKind::Created, path, new));
+                },
+                Some(new) if !new.is_dir && new != old => events.push(event(EventKind::Modified, path, new)),
+                Some(_) => {},
+            }
+        }
+
+        for (path, new) in &newer.entries {
+            if !self.entries.contains_key(path) {
+                events.push(event(EventKind::Created, path, new));
+            }
+        }
+
+        events.sort_by(|a, b| a.path.cmp(&b.path));
+
+        events
+    }
+}
+
+/// Writes a path as a field of a line of text, for state files and journals.
+/// Backslashes, tabs and newlines are escaped as `\\`, `\t` and `\n`, and
+/// bytes that are not valid UTF-8 as `\xNN`.
+pub(crate) fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                c => escaped.push(c),
+            }
+        }
+
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{:02x}", byte);
+        }
+    }
+
+    escaped
+}
+
+/// Reverses `escape_path`.
+pub(crate) fn unescape_path(field: &str) -> io::Result<PathBuf> {
+    let mut bytes = Vec::new();
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('\\') => bytes.push(b'\\'),
+            Some('t') => bytes.push(b'\t'),
+            Some('n') => bytes.push(b'\n'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+
+                bytes.push(u8::from_str_radix(&hex, 16).map_err(invalid)?);
+            },
+            _ => return Err(invalid(field)),
+        }
+    }
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+fn parse_time(time: &str) -> io::Result<Option<SystemTime>> {
+    if time == "-" {
+        return Ok(None);
+    }
+
+    let (secs, nanos) = time.split_once('.').ok_or_else(|| invalid(time))?;
+    let duration = Duration::new(secs.parse().map_err(invalid)?, nanos.parse().map_err(invalid)?);
+
+    Ok(Some(UNIX_EPOCH + duration))
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::{env, process};
+
+    #[test]
+    fn loads_what_was_saved_and_diffs_it() {
+        let dir = env::temp_dir().join(format!("aa-snapshot-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let odd = dir.join(OsStr::from_bytes(b"new\nline \xff"));
+        fs::write(&odd, "a").unwrap();
+
+        let mut snapshot = Snapshot::new();
+        snapshot.insert(&odd, &fs::metadata(&odd).unwrap());
+        snapshot.save(dir.join("state")).unwrap();
+
+        assert_eq!(Snapshot::load(dir.join("state")).unwrap(), snapshot);
+
+        let events = snapshot.diff(&Snapshot::new(), &dir);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind, &events[0].path), (EventKind::Deleted, &odd));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use crate::error::WatcherError;
+use crate::events::WatchEvent;
+use crate::watchers::Watcher;
+
+/// A stream of changes, like the one a `Watcher` reports. Code consuming
+/// events through this trait, e.g. a `Dispatcher`, can be tested with a
+/// `MockWatcher`.
+pub trait EventSource {
+    /// Blocks until a change is detected and returns it.
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError>;
+
+    /// Like `next_event`, but returns `Ok(None)` if no change is detected
+    /// within `timeout`.
+    fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError>;
+
+    /// Blocks until changes are detected and returns all that are available
+    /// without waiting any longer.
+    fn next_batch(&mut self) -> Result<Vec<WatchEvent>, WatcherError> {
+        let mut batch = vec![self.next_event()?];
+
+        while let Some(event) = self.next_event_timeout(Duration::from_secs(0))? {
+            batch.push(event);
+        }
+
+        Ok(batch)
+    }
+
+    /// Whether the source will never report another change, e.g. a
+    /// `MockWatcher` that reported all its events. Ends a `Dispatcher::run`.
+    fn is_finished(&self) -> bool { false }
+}
+
+impl EventSource for Watcher {
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError> { Watcher::next_event(self) }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        Watcher::next_event_timeout(self, timeout)
+    }
+
+    fn next_batch(&mut self) -> Result<Vec<WatchEvent>, WatcherError> { self.watch_batch() }
+}
+
+#[cfg(feature = "serde")]
+impl EventSource for crate::remote::Receiver {
+    fn next_event(&mut self) -> Result<WatchEvent, WatcherError> { crate::remote::Receiver::next_event(self) }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        crate::remote::Receiver::next_event_timeout(self, timeout)
+    }
+}
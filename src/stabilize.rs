@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::events::{EventKind, WatchEvent};
+
+/// Holds back events for files that are still being written, e.g. a large
+/// file being copied in, until they are closed or stop changing.
+///
+/// A file is stable once it was closed after writing, or its size and mtime
+/// did not change for `period`. Further changes meanwhile are merged into the
+/// held event, so a created file is reported as created once.
+pub(crate) struct Stabilizer {
+    period: Duration,
+    // In order of arrival.
+    held: Vec<Held>,
+    // The files closed after writing since the last call to `absorb`.
+    closed: HashSet<PathBuf>,
+}
+
+struct Held {
+    event: WatchEvent,
+    state: (Option<u64>, Option<SystemTime>),
+    since: Instant,
+}
+
+impl Stabilizer {
+    pub(crate) fn new(period: Duration) -> Stabilizer {
+        Stabilizer {
+            period,
+            held: Vec::new(),
+            closed: HashSet::new(),
+        }
+    }
+
+    /// Records that a file was closed after writing, for `IN_CLOSE_WRITE`.
+    pub(crate) fn close(&mut self, path: PathBuf) { self.closed.insert(path); }
+
+    /// How long until a held file may be stable, if any is held.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.held.iter().map(|held| self.period.saturating_sub(held.since.elapsed())).min()
+    }
+
+    /// Holds back the events for files that are written to, and returns the
+    /// others followed by those for files that became stable.
+    pub(crate) fn absorb(&mut self, batch: Vec<WatchEvent>) -> Vec<WatchEvent> {
+        let mut passed = Vec::new();
+
+        for event in batch {
+            match event.kind {
+                EventKind::Created | EventKind::Modified if !event.is_dir => self.hold(event),
+                EventKind::Deleted if !event.is_dir => {
+                    let held = self.held.iter().position(|held| held.event.path == event.path);
+
+                    // A file created and deleted before it was stable was never there.
+                    match held.map(|i| self.held.remove(i)) {
+                        Some(held) if held.event.kind == EventKind::Created => {},
+                        _ => passed.push(event),
+                    }
+                },
+                _ => passed.push(event),
+            }
+        }
+
+        passed.extend(self.release());
+        passed
+    }
+
+    fn hold(&mut self, event: WatchEvent) {
+        let state = file_state(&event.path);
+
+        match self.held.iter_mut().find(|held| held.event.path == event.path) {
+            Some(held) => {
+                held.state = state;
+                held.since = Instant::now();
+            },
+            None => self.held.push(Held {
+                event,
+                state,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    fn release(&mut self) -> Vec<WatchEvent> {
+        let closed = mem::take(&mut self.closed);
+        let period = self.period;
+        let mut released = Vec::new();
+
+        self.held.retain_mut(|held| {
+            let state = file_state(&held.event.path);
+
+            if closed.contains(&held.event.path) || (state == held.state && held.since.elapsed() >= period) {
+                released.push(held.event.clone());
+
+                return false;
+            }
+
+            if state != held.state {
+                held.state = state;
+                held.since = Instant::now();
+            }
+
+            true
+        });
+
+        released
+    }
+}
+
+fn file_state(path: &Path) -> (Option<u64>, Option<SystemTime>) {
+    match fs::metadata(path) {
+        Ok(metadata) => (Some(metadata.len()), metadata.modified().ok()),
+        Err(_) => (None, None),
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::SystemTime;
+
+use crate::events::{EventKind, WatchEvent};
+use crate::runner::Status;
+
+/// Runtime statistics of a `Watcher`, as returned by `Watcher::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WatcherStats {
+    /// The number of active inotify watch descriptors.
+    pub watches: usize,
+    pub created: u64,
+    pub modified: u64,
+    pub deleted: u64,
+    /// How often the kernel's event queue overflowed, losing an unknown
+    /// number of events.
+    pub overflows: u64,
+    /// How often the watcher recovered from an error, when supervised.
+    pub recoveries: u64,
+    pub last_event: Option<SystemTime>,
+}
+
+impl WatcherStats {
+    /// The total number of events seen, before any filtering.
+    pub fn events(&self) -> u64 { self.created + self.modified + self.deleted }
+
+    pub(crate) fn record(&mut self, event: &WatchEvent) {
+        match event.kind {
+            EventKind::Created => self.created += 1,
+            EventKind::Modified => self.modified += 1,
+            EventKind::Deleted => self.deleted += 1,
+            // Counted in `overflows`, `recoveries`, or as the events they
+            // collapse.
+            EventKind::Rescan | EventKind::Recovered | EventKind::Storm => {},
+            // Not a change, and never reported by the watcher itself.
+            EventKind::Timer => {},
+        }
+
+        self.last_event = Some(SystemTime::now());
+    }
+}
+
+/// Statistics of a `Runner`, as returned by `Runner::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunnerStats {
+    /// The number of executions, including retries.
+    pub runs: u64,
+    pub failures: u64,
+    pub retries: u64,
+    /// How often a running command was terminated to start it again for a
+    /// change, in `RestartOnChange` mode.
+    pub restarts: u64,
+    /// The number of failures since the last successful execution.
+    pub consecutive_failures: u64,
+    pub last_failure: Option<SystemTime>,
+    /// The number of events waiting to be run for, and the most that ever
+    /// waited at once.
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+    /// Applying the `Backpressure` policy: the events dropped, the events
+    /// merged into others, and how often `Runner::trigger` waited for room.
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub blocked: u64,
+}
+
+impl RunnerStats {
+    pub(crate) fn record(&mut self, status: Status) {
+        self.runs += 1;
+
+        if status.is_failure() {
+            self.failures += 1;
+            self.consecutive_failures += 1;
+            self.last_failure = Some(SystemTime::now());
+        } else {
+            self.consecutive_failures = 0;
+        }
+    }
+}
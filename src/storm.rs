@@ -0,0 +1,143 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::events::{EventKind, Origin, Storm, WatchEvent};
+
+/// Collapses bursts of events, e.g. from a `git checkout`, into a single
+/// `EventKind::Storm` event.
+///
+/// A storm starts once more than `threshold` events arrived within `window`.
+/// The events up to that point are emitted as usual, later ones are
+/// collected until no event arrived for `window`.
+pub(crate) struct StormDetector {
+    threshold: usize,
+    window: Duration,
+    // When the events of the current window arrived.
+    recent: VecDeque<Instant>,
+    // The number of collected events and their top-level directories.
+    storm: Option<(usize, BTreeSet<PathBuf>)>,
+    last_event: Instant,
+}
+
+impl StormDetector {
+    pub(crate) fn new(threshold: usize, window: Duration) -> StormDetector {
+        StormDetector {
+            threshold,
+            window,
+            recent: VecDeque::new(),
+            storm: None,
+            last_event: Instant::now(),
+        }
+    }
+
+    pub(crate) fn in_storm(&self) -> bool { self.storm.is_some() }
+
+    /// How long until the storm in progress ends, unless more events arrive.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.storm.as_ref().map(|_| self.window.saturating_sub(self.last_event.elapsed()))
+    }
+
+    /// Returns the events that are not collected into a storm.
+    pub(crate) fn absorb(&mut self, batch: Vec<WatchEvent>) -> Vec<WatchEvent> {
+        let now = Instant::now();
+        let mut passed = Vec::new();
+
+        for event in batch {
+            // Lost events are reported regardless, since they call for a rescan.
+            if let EventKind::Rescan | EventKind::Recovered = event.kind {
+                passed.push(event);
+                continue;
+            }
+
+            self.last_event = now;
+
+            while self.recent.front().is_some_and(|t| now.duration_since(*t) > self.window) {
+                self.recent.pop_front();
+            }
+
+            self.recent.push_back(now);
+
+            if self.storm.is_none() && self.recent.len() > self.threshold {
+                self.storm = Some((0, BTreeSet::new()));
+            }
+
+            match &mut self.storm {
+                Some((count, dirs)) => {
+                    *count += 1;
+                    dirs.insert(top_level_dir(&event));
+                },
+                None => passed.push(event),
+            }
+        }
+
+        passed
+    }
+
+    /// Returns the storm event once no event arrived for the window.
+    pub(crate) fn finish(&mut self, root: &Path) -> Option<WatchEvent> {
+        if self.last_event.elapsed() < self.window {
+            return None;
+        }
+
+        let (count, dirs) = self.storm.take()?;
+
+        self.recent.clear();
+
+        Some(WatchEvent {
+            kind: EventKind::Storm,
+            path: root.to_path_buf(),
+            root: root.to_path_buf(),
+            is_dir: true,
+            metadata: None,
+            storm: Some(Storm {
+                count,
+                dirs: dirs.into_iter().collect(),
+            }),
+            origin: Origin::External,
+            timer: None,
+        })
+    }
+}
+
+// The directory directly inside the root containing the event's path, or the
+// root for paths directly inside it.
+fn top_level_dir(event: &WatchEvent) -> PathBuf {
+    let mut components = event.relative_path().components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(dir)), Some(_)) => event.root.join(dir),
+        _ => event.root.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockWatcher;
+    use crate::source::EventSource;
+    use std::thread;
+
+    #[test]
+    fn collapses_events_past_the_threshold() {
+        let mut mock = MockWatcher::new("/project");
+        mock.modify("a").modify("src/b").modify("src/c").rescan().modify("docs/d").modify("e");
+
+        let mut storm = StormDetector::new(2, Duration::from_millis(50));
+        let passed: Vec<EventKind> = storm.absorb(mock.next_batch().unwrap()).iter().map(|event| event.kind).collect();
+
+        assert_eq!(passed, [EventKind::Modified, EventKind::Modified, EventKind::Rescan]);
+        assert!(storm.in_storm());
+        assert!(storm.finish(Path::new("/project")).is_none());
+
+        thread::sleep(Duration::from_millis(60));
+
+        let event = storm.finish(Path::new("/project")).unwrap();
+        let summary = event.storm.unwrap();
+
+        assert_eq!(event.kind, EventKind::Storm);
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.dirs, [PathBuf::from("/project"), PathBuf::from("/project/docs"), PathBuf::from("/project/src")]);
+        assert!(!storm.in_storm());
+    }
+}
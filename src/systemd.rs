@@ -0,0 +1,79 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// The first file descriptor passed by socket activation, SD_LISTEN_FDS_START.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sends a state like `READY=1` to the service manager, as sd_notify(3) does.
+/// Returns `Ok(false)` if not running under systemd with notify access.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+
+    let path = path.to_string_lossy();
+
+    // A leading '@' denotes a socket in the abstract namespace.
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(true)
+}
+
+/// Tells the service manager that startup is complete, or a reload finished.
+pub fn ready() -> io::Result<bool> { notify("READY=1") }
+
+/// Tells the service manager that the service is reloading. Must be followed
+/// by `ready` once done.
+pub fn reloading() -> io::Result<bool> {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1000;
+
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec))
+}
+
+pub fn stopping() -> io::Result<bool> { notify("STOPPING=1") }
+
+/// Tells the service manager that the service is alive. See `watchdog_interval`.
+pub fn watchdog() -> io::Result<bool> { notify("WATCHDOG=1") }
+
+/// The interval the service manager expects `watchdog` to be called within,
+/// if the watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    Some(Duration::from_micros(usec))
+}
+
+/// The file descriptors passed by socket activation, see sd_listen_fds(3).
+pub fn listen_fds() -> Vec<RawFd> {
+    if !for_this_process("LISTEN_PID") {
+        return Vec::new();
+    }
+
+    let count: RawFd = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok()).unwrap_or(0);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+// Whether the variable is set and names this process, so that the setting
+// was not meant for a parent that passed on its environment.
+fn for_this_process(variable: &str) -> bool {
+    env::var(variable).ok().and_then(|pid| pid.parse::<libc::pid_t>().ok()) == Some(unsafe { libc::getpid() })
+}
@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::io::{self, Stdout, Write};
+use std::process::ExitStatus;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, style};
+
+use crate::events::WatchEvent;
+use crate::runner::Status;
+
+// The number of events kept for display.
+const MAX_EVENTS: usize = 100;
+
+// The number of lines of command output kept for display.
+const MAX_OUTPUT_LINES: usize = 10;
+
+/// What the user asked for by pressing a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    /// `p` or space. The display tracks whether it is paused.
+    TogglePause,
+    /// `r`.
+    Reload,
+    /// `q`, Esc or Ctrl-C.
+    Quit,
+}
+
+/// Collects the stderr of the last command for display, see
+/// `Executor::on_output`.
+#[derive(Clone, Default)]
+pub struct Output(Arc<Mutex<String>>);
+
+impl Output {
+    pub fn record(&self, _: ExitStatus, output: &[u8]) {
+        *self.0.lock().unwrap() = String::from_utf8_lossy(output).into_owned();
+    }
+}
+
+/// A full-screen status display, showing the watched roots, the latest
+/// events and the state of the command, and reading keys to pause, reload
+/// or quit.
+///
+/// The terminal is restored once it is dropped.
+pub struct Tui {
+    terminal: RawTerminal<AlternateScreen<Stdout>>,
+    keys: Receiver<Input>,
+    roots: Vec<String>,
+    command: Option<String>,
+    events: VecDeque<(Instant, WatchEvent)>,
+    output: Output,
+    paused: bool,
+}
+
+impl Tui {
+    pub fn new(roots: Vec<String>, command: Option<String>, output: Output) -> io::Result<Tui> {
+        let terminal = AlternateScreen::from(io::stdout()).into_raw_mode()?;
+        let (sender, keys) = mpsc::channel();
+
+        thread::spawn(move || {
+            for key in io::stdin().keys() {
+                let input = match key {
+                    Ok(Key::Char('p')) | Ok(Key::Char(' ')) => Input::TogglePause,
+                    Ok(Key::Char('r')) => Input::Reload,
+                    Ok(Key::Char('q')) | Ok(Key::Esc) | Ok(Key::Ctrl('c')) => Input::Quit,
+                    Ok(_) => continue,
+                    Err(_) => return,
+                };
+
+                if sender.send(input).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Tui {
+            terminal,
+            keys,
+            roots,
+            command,
+            events: VecDeque::new(),
+            output,
+            paused: false,
+        })
+    }
+
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    /// Returns the next key pressed, if any, without blocking.
+    pub fn input(&mut self) -> Option<Input> {
+        let input = self.keys.try_recv().ok()?;
+
+        if input == Input::TogglePause {
+            self.paused = !self.paused;
+        }
+
+        Some(input)
+    }
+
+    pub fn push(&mut self, event: &WatchEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_back();
+        }
+
+        self.events.push_front((Instant::now(), event.clone()));
+    }
+
+    /// Redraws the whole screen. `status` is `None` if no command is run.
+    pub fn draw(&mut self, status: Option<Status>) -> io::Result<()> {
+        // Pseudo-terminals report a size of zero until one is set.
+        let (width, height) = match termion::terminal_size() {
+            Ok((width, height)) if width > 0 && height > 0 => (width, height),
+            _ => (80, 24),
+        };
+        let mut lines = Vec::new();
+
+        let state = if self.paused { "  [paused]" } else { "" };
+        lines.push(format!("{}A'a{}{}", style::Bold, style::Reset, state));
+
+        for root in &self.roots {
+            lines.push(format!("Watching {}", root));
+        }
+
+        if let Some(command) = &self.command {
+            lines.push(format!("Command  {}", command));
+        }
+
+        if let Some(status) = status {
+            lines.push(format!("Status   {}", describe(status)));
+        }
+
+        let output = self.output.0.lock().unwrap().clone();
+        let output: Vec<&str> = output.lines().collect();
+        let output = &output[output.len().saturating_sub(MAX_OUTPUT_LINES)..];
+
+        // Below the header: a blank line and the event list's title, the
+        // output with its title, and the keys.
+        let below = 2 + if output.is_empty() { 0 } else { output.len() + 1 } + 1;
+        let room = (height as usize).saturating_sub(lines.len() + below);
+
+        lines.push(String::new());
+        lines.push(format!("{}Events{}", style::Bold, style::Reset));
+
+        for (time, event) in self.events.iter().take(room) {
+            let path = match &event.storm {
+                Some(storm) => format!("{} changes", storm.count),
+                None => event.relative_path().display().to_string(),
+            };
+
+            lines.push(format!("{:>5}s  {:<9} {}", time.elapsed().as_secs(), event.kind.name(), path));
+        }
+
+        if !output.is_empty() {
+            lines.push(format!("{}Output{}", style::Bold, style::Reset));
+            lines.extend(output.iter().map(|line| line.to_string()));
+        }
+
+        write!(self.terminal, "{}{}", clear::All, cursor::Goto(1, 1))?;
+
+        for line in &lines {
+            write!(self.terminal, "{}\r\n", truncate(line, width as usize))?;
+        }
+
+        write!(self.terminal, "{}p{} pause  {}r{} reload  {}q{} quit",
+               style::Bold, style::Reset, style::Bold, style::Reset, style::Bold, style::Reset)?;
+
+        self.terminal.flush()
+    }
+}
+
+fn describe(status: Status) -> String {
+    match status {
+        Status::Idle => String::from("waiting for changes"),
+        Status::Running => String::from("running"),
+        Status::Exited(status) => match status.code() {
+            Some(code) => format!("exited with code {}", code),
+            None => String::from("killed by a signal"),
+        },
+        Status::Failed => String::from("could not be started"),
+        Status::TimedOut => String::from("timed out"),
+    }
+}
+
+// Cuts a line at the terminal's width, ignoring escape sequences, which
+// do not take up any room.
+fn truncate(line: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut visible = 0;
+    let mut escape = false;
+
+    for c in line.chars() {
+        match c {
+            '\x1b' => escape = true,
+            _ if escape => escape = !c.is_ascii_alphabetic(),
+            _ if visible == width => continue,
+            _ => visible += 1,
+        }
+
+        result.push(c);
+    }
+
+    result
+}
@@ -1,30 +1,62 @@
 extern crate inotify;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::content::ContentCache;
+use crate::dispatcher::{Control, Dispatcher};
+use crate::error::{self, WatcherError};
+use crate::events::{EventKind, Metadata, Origin, WatchEvent};
+use crate::filter::Filter;
+use crate::guard::{OwnWrites, WriteGuard};
+use crate::journal::Journal;
+use crate::kind::FileKind;
+use crate::log::{Level, WatcherLog};
+use crate::pause::{PauseHandle, ReplayPolicy};
+use crate::snapshot::Snapshot;
+use crate::stabilize::Stabilizer;
+use crate::stats::WatcherStats;
+use crate::storm::StormDetector;
+
 use inotify::{
+    Event,
     EventMask,
     Inotify,
     WatchDescriptor,
     WatchMask,
 };
 
-// Macro alias for slog info to first check for a logger.
+// Macro alias for an info message to first check for a logger.
 macro_rules! watcher_info(
-    ($w:expr, #$tag:expr, $($args:tt)+) => {
+    ($w:expr, $($args:tt)+) => {
         if let Some(logger) = &$w.logger {
-            info!(logger, $tag, $($args)+)
+            logger.log(Level::Info, format_args!($($args)+))
         }
     };
+);
+
+// Macro alias for a warning to first check for a logger.
+macro_rules! watcher_warn(
     ($w:expr, $($args:tt)+) => {
         if let Some(logger) = &$w.logger {
-            info!(logger, $($args)+)
+            logger.log(Level::Warning, format_args!($($args)+))
         }
     };
 );
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Traversal {
     RECURSIVE,
     HEURISTIC,
@@ -35,149 +67,2133 @@ pub enum WatcherType {
     DIRECTORY,
 }
 
+/// What a recursive directory watcher does when the inotify watch limit is
+/// reached during traversal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitPolicy {
+    /// Fail with `WatcherError::WatchLimit`.
+    Fail,
+    /// Fall back to watching only the root and its immediate subdirectories.
+    Degrade,
+}
+
+/// How directory traversal treats symbolic links to directories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Follow all symlinks. A directory reachable through several links,
+    /// also ones created later, is only watched once, under the first path
+    /// found.
+    Follow,
+    /// Never follow symlinks.
+    NoFollow,
+    /// Follow symlinks directly inside the root, but none below them.
+    FollowTopLevel,
+}
+
+/// Which hidden (dot-prefixed) files and directories a directory watcher
+/// ignores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HiddenPolicy {
+    /// Watch and report hidden files and directories like any other.
+    IncludeAll,
+    /// Do not watch hidden directories, but report hidden files.
+    ExcludeDirs,
+    /// Neither watch hidden directories nor report hidden files.
+    ExcludeAll,
+}
+
+// Options shared by the initial traversal and later rescans.
+#[derive(Clone)]
+struct WalkOptions {
+    symlinks: SymlinkPolicy,
+    hidden: HiddenPolicy,
+    // Directories neither watched nor reported, below the root.
+    ignored: Vec<PathBuf>,
+}
+
+/// The number of subdirectories watched in addition to the root in
+/// `Traversal::HEURISTIC` mode, unless configured otherwise.
+pub const DEFAULT_HEURISTIC_DIRS: usize = 16;
+
+// The number of events between two rebalances of the heuristic watch set.
+const REBALANCE_EVENTS: usize = 32;
+
+/// The initial size in bytes of the buffer inotify events are read into,
+/// unless configured otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// The size in bytes the event buffer may grow to, unless configured otherwise.
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 256 * 1024;
+
+// The size of an event with the longest possible name: the header of
+// `struct inotify_event`, NAME_MAX bytes and the terminating NUL.
+const MAX_EVENT_SIZE: usize = EVENT_HEADER_SIZE + 255 + 1;
+
+const EVENT_HEADER_SIZE: usize = 16;
+
+// The number of consecutive reads that fill the buffer before it is grown.
+const FULL_READS: usize = 4;
+
+// How often files added with `Watcher::add_file` are looked for while missing.
+const MISSING_FILE_POLL: Duration = Duration::from_millis(250);
+
+// How often a supervised watcher tries to recover from one error, and the
+// delay before the second attempt, doubled for each further one.
+const MAX_ATTEMPTS: usize = 5;
+const RECOVERY_DELAY: Duration = Duration::from_millis(100);
+
+// How often a supervised watcher recovers within `RECOVERY_WINDOW` before
+// giving up, since an error that keeps coming back would otherwise have it
+// rebuild its watches forever.
+const MAX_RECOVERIES: usize = 5;
+const RECOVERY_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct WatcherBuilder {
+    path: PathBuf,
+    traversal: Traversal,
+    limit_policy: LimitPolicy,
+    heuristic_dirs: usize,
+    content_check: Option<u64>,
+    walk: WalkOptions,
+    emit_existing: bool,
+    with_metadata: bool,
+    rescan_on_overflow: bool,
+    supervise: bool,
+    buffer_size: usize,
+    max_buffer_size: usize,
+    storm: Option<(usize, Duration)>,
+    stabilize: Option<Duration>,
+    own_writes: OwnWrites,
+    state_file: Option<PathBuf>,
+    kinds: Vec<FileKind>,
+    max_depth: Option<usize>,
+    filter: Option<Filter>,
+    log_stats: Option<Duration>,
+    journal: Option<PathBuf>,
+    logger: Option<Arc<dyn WatcherLog>>,
+}
+
+impl WatcherBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> WatcherBuilder {
+        WatcherBuilder {
+            path: path.as_ref().to_path_buf(),
+            traversal: Traversal::HEURISTIC,
+            limit_policy: LimitPolicy::Fail,
+            heuristic_dirs: DEFAULT_HEURISTIC_DIRS,
+            content_check: None,
+            walk: WalkOptions {
+                symlinks: SymlinkPolicy::Follow,
+                hidden: HiddenPolicy::ExcludeDirs,
+                ignored: Vec::new(),
+            },
+            emit_existing: false,
+            with_metadata: false,
+            rescan_on_overflow: false,
+            supervise: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            storm: None,
+            stabilize: None,
+            own_writes: OwnWrites::Tag,
+            state_file: None,
+            kinds: Vec::new(),
+            max_depth: None,
+            filter: None,
+            log_stats: None,
+            journal: None,
+            logger: None,
+        }
+    }
+
+    pub fn traversal(mut self, traversal: Traversal) -> WatcherBuilder {
+        self.traversal = traversal;
+        self
+    }
+
+    pub fn limit_policy(mut self, policy: LimitPolicy) -> WatcherBuilder {
+        self.limit_policy = policy;
+        self
+    }
+
+    /// Sets how many subdirectories `Traversal::HEURISTIC` watches besides the root.
+    /// Finding the most recently modified ones walks the tree up to
+    /// `max_depth` every few events, unless `count` is 0.
+    pub fn heuristic_dirs(mut self, count: usize) -> WatcherBuilder {
+        self.heuristic_dirs = count;
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> WatcherBuilder {
+        self.walk.symlinks = policy;
+        self
+    }
+
+    pub fn hidden(mut self, policy: HiddenPolicy) -> WatcherBuilder {
+        self.walk.hidden = policy;
+        self
+    }
+
+    /// Neither watches nor reports the directory `dir`, relative to the root,
+    /// or anything below it. Can be called repeatedly.
+    pub fn ignore_dir<P: AsRef<Path>>(mut self, dir: P) -> WatcherBuilder {
+        self.walk.ignored.push(Path::new(&self.path).join(dir));
+        self
+    }
+
+    /// Only watches directories up to `depth` levels below the root, which
+    /// is at depth 0. Applies to the initial traversal as well as to
+    /// directories created later.
+    pub fn max_depth(mut self, depth: usize) -> WatcherBuilder {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Emits a `Created` event for every existing file in the watched
+    /// directories before any changes. With a `state_file`, only deletions
+    /// are emitted from it in addition.
+    pub fn emit_existing(mut self, emit: bool) -> WatcherBuilder {
+        self.emit_existing = emit;
+        self
+    }
+
+    /// Attaches the size, mtime and file type to every event. Costs a `stat`
+    /// per event, so it is off by default.
+    pub fn with_metadata(mut self, with_metadata: bool) -> WatcherBuilder {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    /// Only reports events matching `filter`. Calling this again requires
+    /// events to match both filters.
+    pub fn filter(mut self, filter: Filter) -> WatcherBuilder {
+        self.filter = Some(match self.filter.take() {
+            Some(previous) => previous.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    /// Only reports files of this kind, as detected from their content. Can
+    /// be called repeatedly to allow several kinds. Events for directories,
+    /// deleted and unreadable files are always reported.
+    pub fn filter_kind(mut self, kind: FileKind) -> WatcherBuilder {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// Hashes files up to `max_size` bytes on change and drops events for
+    /// files whose content is unchanged. See `ContentCache`.
+    pub fn content_check(mut self, max_size: u64) -> WatcherBuilder {
+        self.content_check = Some(max_size);
+        self
+    }
+
+    /// After the kernel's event queue overflowed, re-walks the tree to watch
+    /// directories created in the meantime and forget removed ones. Each of
+    /// them is reported as a `Created` or `Deleted` event following the
+    /// `Rescan` event.
+    pub fn rescan_on_overflow(mut self, rescan: bool) -> WatcherBuilder {
+        self.rescan_on_overflow = rescan;
+        self
+    }
+
+    /// Recovers from errors reading events, e.g. `EBADF` after the watched
+    /// tree's filesystem was unmounted, instead of returning them: the
+    /// inotify instance is replaced, the tree and the added files are
+    /// watched again, and a `Recovered` event is reported, since changes
+    /// may have been missed meanwhile. Fails once that did not succeed after
+    /// a few attempts, or after recovering 5 times within a minute. Each
+    /// recovery within that minute waits longer before starting.
+    pub fn supervise(mut self, supervise: bool) -> WatcherBuilder {
+        self.supervise = supervise;
+        self
+    }
+
+    /// Sets the initial size in bytes of the event buffer. It is raised to fit at
+    /// least one event with the longest possible file name.
+    pub fn buffer_size(mut self, size: usize) -> WatcherBuilder {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Sets the size in bytes the event buffer may grow to when reads keep
+    /// filling it. Growth is disabled if this is not above `buffer_size`.
+    pub fn max_buffer_size(mut self, size: usize) -> WatcherBuilder {
+        self.max_buffer_size = size;
+        self
+    }
+
+    /// Collapses bursts of more than `threshold` events within `window` into
+    /// a single `EventKind::Storm` event, emitted once no event arrived for
+    /// `window`.
+    pub fn storm(mut self, threshold: usize, window: Duration) -> WatcherBuilder {
+        self.storm = Some((threshold, window));
+        self
+    }
+
+    /// Holds back events for created and modified files until they were
+    /// closed after writing, or their size and mtime did not change for
+    /// `period`, so that e.g. a large file being copied in is reported once
+    /// it is complete.
+    pub fn stabilize(mut self, period: Duration) -> WatcherBuilder {
+        self.stabilize = Some(period);
+        self
+    }
+
+    /// What to do with the events for paths marked with the watcher's
+    /// `WriteGuard`. They are tagged with `Origin::Own` by default.
+    pub fn own_writes(mut self, policy: OwnWrites) -> WatcherBuilder {
+        self.own_writes = policy;
+        self
+    }
+
+    /// Persists a `Snapshot` of the tree to `path` when the watcher is
+    /// dropped. If the file exists when building, the changes made since it
+    /// was saved are emitted before any live changes.
+    pub fn state_file<P: AsRef<Path>>(mut self, path: P) -> WatcherBuilder {
+        self.state_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Logs the watcher's statistics at most once per `interval`. The
+    /// interval is checked whenever events are read.
+    pub fn log_stats(mut self, interval: Duration) -> WatcherBuilder {
+        self.log_stats = Some(interval);
+        self
+    }
+
+    /// Records every event the watcher reports to a `Journal` at `path`,
+    /// replacing an existing file.
+    pub fn journal<P: AsRef<Path>>(mut self, path: P) -> WatcherBuilder {
+        self.journal = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Registers the logger before the initial traversal, so that messages
+    /// about e.g. degraded mode are not lost.
+    pub fn log<L: WatcherLog + 'static>(mut self, logger: L) -> WatcherBuilder {
+        self.logger = Some(Arc::new(logger));
+        self
+    }
+
+    #[cfg(feature = "slog")]
+    pub fn logger(self, logger: slog::Logger) -> WatcherBuilder { self.log(logger) }
+
+    /// Walks the tree like `build` would, but without adding any watches.
+    /// In `Traversal::HEURISTIC` mode, the most recently modified directories
+    /// are assumed to be watched. `LimitPolicy::Degrade` is not applied.
+    pub fn plan(&self) -> Result<Plan, WatcherError> {
+        let mut dirs = collect_dirs(&self.path, self.max_depth, &self.walk)?;
+
+        if self.traversal == Traversal::HEURISTIC {
+            let mut subdirs: Vec<(Option<SystemTime>, PathBuf)> = dirs.split_off(1.min(dirs.len()))
+                .into_iter()
+                .map(|d| (fs::metadata(&d).and_then(|m| m.modified()).ok(), d))
+                .collect();
+
+            subdirs.sort_by_key(|d| Reverse(d.0));
+            dirs.extend(subdirs.into_iter().take(self.heuristic_dirs).map(|d| d.1));
+        }
+
+        Ok(Plan {
+            dirs,
+            limit: max_user_watches(),
+            in_use: watches_in_use(),
+        })
+    }
+
+    pub fn build(self) -> Result<Watcher, WatcherError> {
+        let mut watch_mask = WatchMask::MODIFY |
+                             WatchMask::CREATE |
+                             WatchMask::DELETE;
+
+        if self.stabilize.is_some() {
+            watch_mask |= WatchMask::CLOSE_WRITE;
+        }
+
+        let mut watcher = Watcher {
+            watcher_type: WatcherType::DIRECTORY,
+            root: self.path.clone(),
+            notify: Inotify::init().map_err(WatcherError::Init)?,
+            watch_mask,
+            logger: self.logger,
+            paths: None,
+            limit_policy: self.limit_policy,
+            degraded: false,
+            heuristic: None,
+            pending: VecDeque::new(),
+            content: self.content_check.map(ContentCache::new),
+            walk: self.walk.clone(),
+            pause: PauseHandle::new(),
+            guard: WriteGuard::new(),
+            own_writes: self.own_writes,
+            with_metadata: self.with_metadata,
+            rescan_on_overflow: self.rescan_on_overflow,
+            supervise: self.supervise,
+            buffer: vec![0; self.buffer_size.max(MAX_EVENT_SIZE)],
+            max_buffer_size: self.max_buffer_size,
+            full_reads: 0,
+            storm: self.storm.map(|(threshold, window)| StormDetector::new(threshold, window)),
+            stabilizer: self.stabilize.map(Stabilizer::new),
+            state_file: self.state_file,
+            kinds: self.kinds,
+            max_depth: self.max_depth,
+            filter: self.filter,
+            stats: WatcherStats::default(),
+            recoveries: VecDeque::new(),
+            log_stats: self.log_stats.map(|interval| (interval, Instant::now())),
+            files: HashMap::new(),
+            missing_files: Vec::new(),
+            file_ids: HashMap::new(),
+            journal: self.journal.map(Journal::create).transpose()?,
+        };
+
+        if let Traversal::HEURISTIC = self.traversal {
+            watcher.heuristic = Some(Heuristic {
+                root: self.path,
+                budget: self.heuristic_dirs,
+                activity: HashMap::new(),
+                events: 0,
+                last_rebalance: UNIX_EPOCH,
+            });
+        }
+
+        watcher.watch_tree()?;
+
+        // Reported by `emit_existing`, so not again by the state file.
+        let mut existing = HashSet::new();
+
+        if self.emit_existing {
+            let mut dirs: Vec<PathBuf> = watcher.paths.iter().flat_map(HashMap::values).cloned().collect();
+            dirs.sort();
+
+            for dir in dirs {
+                for path in existing_files(&dir, &self.walk)? {
+                    existing.insert(path.clone());
+                    watcher.queue_initial(WatchEvent {
+                        kind: EventKind::Created,
+                        path,
+                        root: watcher.root.clone(),
+                        is_dir: false,
+                        metadata: None,
+                        storm: None,
+                        origin: Origin::External,
+                        timer: None,
+                    });
+                }
+            }
+        }
+
+        if let Some(state_file) = watcher.state_file.clone() {
+            match Snapshot::load(&state_file) {
+                Ok(previous) => {
+                    for event in previous.diff(&watcher.snapshot()?, &watcher.root) {
+                        if event.kind == EventKind::Deleted || !existing.contains(&event.path) {
+                            watcher.queue_initial(event);
+                        }
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => watcher_warn!(watcher, "Ignoring state file {:?}: {}", state_file, e),
+            }
+        }
+
+        Ok(watcher)
+    }
+}
+
+/// The directories a `WatcherBuilder` would watch, as returned by
+/// `WatcherBuilder::plan`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub dirs: Vec<PathBuf>,
+    /// The per-user watch limit, see `max_user_watches`.
+    pub limit: Option<usize>,
+    /// The watches already added by processes of the current user, if they
+    /// could be counted.
+    pub in_use: Option<usize>,
+}
+
+impl Plan {
+    /// The number of watches that would be added, one per directory.
+    pub fn watches(&self) -> usize { self.dirs.len() }
+
+    /// Whether the watches would fit into what is left of the limit, if known.
+    pub fn fits(&self) -> Option<bool> {
+        let available = self.limit?.saturating_sub(self.in_use.unwrap_or(0));
+
+        Some(self.watches() <= available)
+    }
+}
+
 pub struct Watcher {
     watcher_type: WatcherType,
+    root: PathBuf,
     notify: Inotify,
     watch_mask: WatchMask,
-    logger: Option<slog::Logger>,
-    paths: Option<HashMap<WatchDescriptor, String>>
+    logger: Option<Arc<dyn WatcherLog>>,
+    paths: Option<HashMap<WatchDescriptor, PathBuf>>,
+    limit_policy: LimitPolicy,
+    degraded: bool,
+    heuristic: Option<Heuristic>,
+    // Events read from inotify but not yet returned by `next_event`.
+    pending: VecDeque<WatchEvent>,
+    content: Option<ContentCache>,
+    walk: WalkOptions,
+    pause: PauseHandle,
+    guard: WriteGuard,
+    own_writes: OwnWrites,
+    with_metadata: bool,
+    rescan_on_overflow: bool,
+    supervise: bool,
+    buffer: Vec<u8>,
+    max_buffer_size: usize,
+    // The number of consecutive reads that filled the buffer.
+    full_reads: usize,
+    storm: Option<StormDetector>,
+    stabilizer: Option<Stabilizer>,
+    state_file: Option<PathBuf>,
+    // The file kinds to report, or all if empty.
+    kinds: Vec<FileKind>,
+    max_depth: Option<usize>,
+    filter: Option<Filter>,
+    stats: WatcherStats,
+    // When supervised, when the watcher recovered within `RECOVERY_WINDOW`.
+    recoveries: VecDeque<Instant>,
+    // The interval to log statistics at, and when they were last logged.
+    log_stats: Option<(Duration, Instant)>,
+    // Files added with `add_file`, by their watches, and those that
+    // disappeared and are not watched until they reappear.
+    files: HashMap<WatchDescriptor, PathBuf>,
+    missing_files: Vec<PathBuf>,
+    // Every file added with `add_file`, by identity.
+    file_ids: HashMap<FileId, PathBuf>,
+    journal: Option<Journal>,
+}
+
+// State of the `Traversal::HEURISTIC` watch set.
+struct Heuristic {
+    root: PathBuf,
+    budget: usize,
+    // Events observed per watched subdirectory since the last rebalance.
+    activity: HashMap<PathBuf, usize>,
+    events: usize,
+    last_rebalance: SystemTime,
 }
 
 impl Watcher {
-    pub fn file_watcher(file: &str) -> Result<Watcher, io::Error> {
-        let mut inotify = Inotify::init()?;
+    pub fn file_watcher<P: AsRef<Path>>(file: P) -> Result<Watcher, WatcherError> {
+        let mut inotify = Inotify::init().map_err(WatcherError::Init)?;
         let watch_mask = WatchMask::MODIFY | WatchMask::DELETE;
 
-        inotify.add_watch(file, watch_mask)?;
+        error::add_watch(&mut inotify, file.as_ref(), watch_mask)?;
 
         Ok(Watcher {
             watcher_type: WatcherType::FILE,
+            root: file.as_ref().to_path_buf(),
             notify: inotify,
-            watch_mask: watch_mask,
+            watch_mask,
             logger: None,
             paths: None,
+            limit_policy: LimitPolicy::Fail,
+            degraded: false,
+            heuristic: None,
+            pending: VecDeque::new(),
+            content: None,
+            walk: WalkOptions {
+                symlinks: SymlinkPolicy::NoFollow,
+                hidden: HiddenPolicy::IncludeAll,
+                ignored: Vec::new(),
+            },
+            pause: PauseHandle::new(),
+            guard: WriteGuard::new(),
+            own_writes: OwnWrites::Tag,
+            with_metadata: false,
+            rescan_on_overflow: false,
+            supervise: false,
+            buffer: vec![0; DEFAULT_BUFFER_SIZE],
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            full_reads: 0,
+            storm: None,
+            stabilizer: None,
+            state_file: None,
+            kinds: Vec::new(),
+            max_depth: None,
+            filter: None,
+            stats: WatcherStats::default(),
+            recoveries: VecDeque::new(),
+            log_stats: None,
+            files: HashMap::new(),
+            missing_files: Vec::new(),
+            file_ids: HashMap::new(),
+            journal: None,
         })
     }
 
-    pub fn dir_watcher(path: &str, trav: Traversal) -> Result<Watcher, io::Error> {
-        let mut inotify = Inotify::init()?;
-        let watch_mask = WatchMask::MODIFY |
-                         WatchMask::CREATE |
-                         WatchMask::DELETE;
+    pub fn dir_watcher<P: AsRef<Path>>(path: P, trav: Traversal) -> Result<Watcher, WatcherError> {
+        WatcherBuilder::new(path).traversal(trav).build()
+    }
 
-        let paths = match trav {
-            Traversal::RECURSIVE => {
-                let mut paths = HashMap::new();
+    pub fn builder<P: AsRef<Path>>(path: P) -> WatcherBuilder { WatcherBuilder::new(path) }
 
-                for entry in WalkDir::new(path)
-                    .follow_links(true)
-                    .into_iter()
-                    .filter_entry(|e| !is_hidden(e) && e.file_type().is_dir()) {
-                        let entry = entry?;
-                        let path = entry.path();
-                        let wd = inotify.add_watch(path, watch_mask)?;
+    /// Whether the watch limit forced the watcher to skip nested directories.
+    pub fn is_degraded(&self) -> bool { self.degraded }
 
-                        if let Some(path) = path.to_str() {
-                            paths.insert(wd, String::from(path));
-                        }
-                }
+    /// The watched directory, or file for file watchers.
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// Watches `file` directly, in addition to the tree, and reports its
+    /// changes in the same stream, e.g. a `Cargo.toml` next to a watched
+    /// `src/`.
+    ///
+    /// Unlike changes seen through its directory, the watch follows atomic
+    /// saves, which rename another file over it or move it away first: the
+    /// replacement is reported as modified. A file that disappears is
+    /// reported as deleted, and as created once it reappears.
+    ///
+    /// Its changes are reported once and under this path, also if the file
+    /// is inside the tree or was already added through another path, e.g.
+    /// a symlinked directory. Adding it again does nothing.
+    pub fn add_file<P: AsRef<Path>>(&mut self, file: P) -> Result<(), WatcherError> {
+        let file = file.as_ref();
+
+        if !file.is_file() {
+            return Err(WatcherError::PathNotFound(file.to_path_buf()));
+        }
+
+        let id = file_id(file);
+
+        if let Some(added) = id.as_ref().and_then(|id| self.file_ids.get(id)) {
+            watcher_info!(self, "Already watching file {:?} as {:?}", file, added);
+
+            return Ok(());
+        }
+
+        watcher_info!(self, "Watching file: {:?}", file);
 
-                Some(paths)
+        let wd = error::add_watch(&mut self.notify, file, file_mask())?;
+        self.files.insert(wd, file.to_path_buf());
+
+        if let Some(id) = id {
+            self.file_ids.insert(id, file.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    /// Stops watching a file added with `add_file`, also if it is missing.
+    /// Returns `false` if it was never added.
+    pub fn remove_file<P: AsRef<Path>>(&mut self, file: P) -> bool {
+        let file = file.as_ref();
+        let file = file_id(file).and_then(|id| self.file_ids.get(&id).cloned()).unwrap_or_else(|| file.to_path_buf());
+        let file = file.as_path();
+        let missing = self.missing_files.len();
+
+        self.file_ids.retain(|_, added| added != file);
+
+        self.missing_files.retain(|missing| missing != file);
+
+        let wd = self.files.iter().find(|(_, path)| *path == file).map(|(wd, _)| wd.clone());
+
+        if let Some(wd) = &wd {
+            watcher_info!(self, "No longer watching file: {:?}", file);
+
+            self.files.remove(wd);
+            let _ = self.notify.rm_watch(wd.clone());
+        }
+
+        wd.is_some() || self.missing_files.len() < missing
+    }
+
+    // Queues an event found before watching began.
+    fn queue_initial(&mut self, mut event: WatchEvent) {
+        if self.with_metadata && event.kind != EventKind::Deleted {
+            event.metadata = Metadata::read(&event.path);
+        }
+
+        if !self.filter.as_ref().map_or(true, |f| f.matches(&event)) || !self.kind_allowed(&event) {
+            return;
+        }
+
+        // Seeds the content cache, so a later touch is not reported.
+        if self.content.as_mut().map(|c| c.changed(&event)).unwrap_or(true) {
+            self.record(&[event.clone()]);
+            self.pending.push_back(event);
+        }
+    }
+
+    // Appends the events to the journal, if any.
+    fn record(&mut self, events: &[WatchEvent]) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        if let Err(e) = events.iter().try_for_each(|event| journal.record(event)) {
+            watcher_warn!(self, "Failed to write to the journal, no longer recording: {}", e);
+
+            self.journal = None;
+        }
+    }
+
+    // Watches the tree as configured, when building the watcher or after
+    // replacing its inotify instance.
+    fn watch_tree(&mut self) -> Result<(), WatcherError> {
+        if let Some(heuristic) = &mut self.heuristic {
+            // Promotes every directory again, as when building.
+            heuristic.last_rebalance = UNIX_EPOCH;
+
+            let wd = error::add_watch(&mut self.notify, &self.root, self.watch_mask)?;
+            self.paths = Some(HashMap::from([(wd, self.root.clone())]));
+
+            return self.rebalance();
+        }
+
+        let paths = match self.add_watches(&collect_dirs(&self.root, self.depth(), &self.walk)?) {
+            Err(WatcherError::WatchLimit { watched, requested, limit, .. })
+                if self.limit_policy == LimitPolicy::Degrade && !self.degraded => {
+                watcher_warn!(self, "Watch limit reached after {} of {} directories \
+                                     (max_user_watches: {:?}), watching top-level \
+                                     directories only", watched, requested, limit);
+
+                self.degraded = true;
+
+                self.add_watches(&collect_dirs(&self.root, self.depth(), &self.walk)?)?
             },
-            Traversal::HEURISTIC => {
-                inotify.add_watch(path, watch_mask)?;
+            result => result?,
+        };
 
-                None
-            }
+        self.paths = Some(paths);
+
+        Ok(())
+    }
+
+    fn add_watches(&mut self, dirs: &[PathBuf]) -> Result<HashMap<WatchDescriptor, PathBuf>, WatcherError> {
+        let mut paths: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+
+        for (watched, dir) in dirs.iter().enumerate() {
+            let wd = match error::add_watch(&mut self.notify, dir, self.watch_mask) {
+                Ok(wd) => wd,
+                Err(e) => {
+                    // Roll back, so a caller falling back to fewer watches has the budget.
+                    for wd in paths.keys() {
+                        let _ = self.notify.rm_watch(wd.clone());
+                    }
+
+                    return Err(match e {
+                        WatcherError::WatchLimit { path, limit, .. } => {
+                            WatcherError::WatchLimit { path, watched, requested: dirs.len(), limit }
+                        },
+                        e => e,
+                    });
+                }
+            };
+
+            paths.insert(wd, dir.clone());
+        }
+
+        Ok(paths)
+    }
+
+    // Promotes subdirectories modified since the last rebalance into the
+    // heuristic watch set, demoting the least active ones to stay in budget.
+    fn rebalance(&mut self) -> Result<(), WatcherError> {
+        let depth = self.depth();
+        let (heuristic, paths) = match (&mut self.heuristic, &mut self.paths) {
+            (Some(heuristic), Some(paths)) => (heuristic, paths),
+            _ => return Ok(()),
         };
 
-        Ok(Watcher {
-            watcher_type: WatcherType::DIRECTORY,
-            notify: inotify,
-            watch_mask: watch_mask,
-            logger: None,
-            paths: paths,
-        })
+        // Nothing could be promoted, so the tree is not walked.
+        if heuristic.budget == 0 {
+            heuristic.activity.clear();
+            heuristic.events = 0;
+
+            return Ok(());
+        }
+
+        let watched: Vec<PathBuf> = paths.values()
+                                        .filter(|p| **p != heuristic.root)
+                                        .cloned()
+                                        .collect();
+
+        let mut candidates: Vec<(SystemTime, PathBuf)> = collect_dirs(&heuristic.root, depth, &self.walk)?
+            .into_iter()
+            .skip(1)
+            .filter(|d| !watched.contains(d))
+            .filter_map(|d| {
+                let mtime = fs::metadata(&d).and_then(|m| m.modified()).ok()?;
+
+                if mtime > heuristic.last_rebalance { Some((mtime, d)) } else { None }
+            })
+            .collect();
+
+        candidates.sort_by_key(|c| Reverse(c.0));
+        candidates.truncate(heuristic.budget);
+
+        // The least active watched directories make room for the candidates.
+        let mut demotable: Vec<(usize, PathBuf)> = watched.iter()
+            .map(|d| (heuristic.activity.get(d).cloned().unwrap_or(0), d.clone()))
+            .collect();
+        demotable.sort();
+
+        let free = heuristic.budget.saturating_sub(watched.len());
+        let demote = candidates.len().saturating_sub(free).min(demotable.len());
+
+        for (_, dir) in demotable.into_iter().take(demote) {
+            if let Some(wd) = paths.iter().find(|(_, p)| **p == dir).map(|(wd, _)| wd.clone()) {
+                watcher_info!(self, "Demoting directory: {:?}", dir);
+
+                let _ = self.notify.rm_watch(wd.clone());
+                paths.remove(&wd);
+            }
+        }
+
+        let room = heuristic.budget.saturating_sub(paths.len().saturating_sub(1));
+
+        for (_, dir) in candidates.into_iter().take(room) {
+            match error::add_watch(&mut self.notify, &dir, self.watch_mask) {
+                Ok(wd) if paths.contains_key(&wd) => continue,
+                Ok(wd) => {
+                    watcher_info!(self, "Promoting directory: {:?}", dir);
+
+                    paths.insert(wd, dir);
+                },
+                Err(WatcherError::WatchLimit { .. }) => break,
+                Err(WatcherError::PathNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        heuristic.activity.clear();
+        heuristic.events = 0;
+        heuristic.last_rebalance = SystemTime::now();
+
+        Ok(())
     }
 
-    pub fn watch(&mut self) -> Result<(bool), io::Error> {
-        match &self.watcher_type {
-            WatcherType::FILE => self.file_event_loop(),
-            WatcherType::DIRECTORY => self.dir_event_loop(),
+    // Records activity for the heuristic watch set, rebalancing periodically.
+    fn record_activity(&mut self, wd: &WatchDescriptor) -> Result<(), WatcherError> {
+        let rebalance = match (&mut self.heuristic, &self.paths) {
+            (Some(heuristic), Some(paths)) => {
+                if let Some(dir) = paths.get(wd) {
+                    *heuristic.activity.entry(dir.clone()).or_insert(0) += 1;
+                }
+
+                heuristic.events += 1;
+                heuristic.events >= REBALANCE_EVENTS
+            },
+            _ => false,
+        };
+
+        if rebalance {
+            self.rebalance()?;
         }
+
+        Ok(())
+    }
+
+    /// Blocks until a change is detected. Returns `Ok(true)` for every change.
+    pub fn watch(&mut self) -> Result<bool, WatcherError> {
+        self.next_event().map(|_| true)
     }
 
-    pub fn register_logger(&mut self, logger: slog::Logger) { self.logger = Some(logger); }
+    /// Blocks until a change is detected and returns it. Events read in the
+    /// same batch are buffered and returned by subsequent calls.
+    pub fn next_event(&mut self) -> Result<WatchEvent, WatcherError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            let batch = self.read_batch(None)?;
+            self.pending.extend(batch);
+        }
+    }
 
-    fn dir_event_loop(&mut self) -> Result<(bool), io::Error> {
-        let mut buffer = [0u8; 4096];
+    /// Like `next_event`, but returns `Ok(None)` if no change is detected
+    /// within `timeout`. A paused watcher still blocks until it is resumed.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, WatcherError> {
+        let deadline = Instant::now() + timeout;
 
         loop {
-            let events = self.notify.read_events_blocking(&mut buffer)?;
-
-            for event in events {
-                if event.mask.contains(EventMask::CREATE) {
-                    if event.mask.contains(EventMask::ISDIR) {
-                        watcher_info!(self, "Directory created: {:?}", event.name);
-
-                        if let (Some(paths), Some(name)) = (&mut self.paths, event.name) {
-                            if let Some(name) = name.to_str() {
-                                if !name.starts_with(".") {
-                                    let wd = event.wd;
-
-                                    if let Some(path) = paths.get(&wd) {
-                                        let new_path = path.to_owned() + "/" + name;
-                                        watcher_info!(self, "Watching new directory: {}", new_path);
-
-                                        let wd = self.notify.add_watch(&new_path, self.watch_mask)?;
-                                        paths.insert(wd, new_path);
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        watcher_info!(self, "File created: {:?}", event.name);
-                    }
-                } else if event.mask.contains(EventMask::DELETE) {
-                    if event.mask.contains(EventMask::ISDIR) {
-                        watcher_info!(self, "Directory deleted: {:?}", event.name);
-                    } else {
-                        watcher_info!(self, "File deleted: {:?}", event.name);
-                    }
-                } else if event.mask.contains(EventMask::MODIFY) {
-                    if event.mask.contains(EventMask::ISDIR) {
-                        watcher_info!(self, "Directory modified: {:?}", event.name);
-                    } else {
-                        watcher_info!(self, "File modified: {:?}", event.name);
-                    }
-                }
-                return Ok(true);
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let batch = self.read_batch(Some(remaining))?;
+
+            if batch.is_empty() && remaining == Duration::from_secs(0) {
+                return Ok(None);
             }
+
+            self.pending.extend(batch);
         }
     }
 
-    fn file_event_loop(&mut self) -> Result<(bool), io::Error> {
-        let mut buffer = [0u8; 4096];
+    /// Blocks until changes are detected and returns all of them, including
+    /// any left over from previous calls to `next_event`.
+    pub fn watch_batch(&mut self) -> Result<Vec<WatchEvent>, WatcherError> {
+        if !self.pending.is_empty() {
+            return Ok(self.pending.drain(..).collect());
+        }
 
         loop {
-            let events = self.notify.read_events_blocking(&mut buffer)?;
+            let batch = self.read_batch(None)?;
 
-            for event in events {
-                if event.mask.contains(EventMask::MODIFY) {
-                    watcher_info!(self, "File modified");
-                } else {
-                    watcher_info!(self, "Unexpected event: {:?}", event.name);
-                }
+            if !batch.is_empty() {
+                return Ok(batch);
+            }
+        }
+    }
+
+    pub fn register_log<L: WatcherLog + 'static>(&mut self, logger: L) { self.logger = Some(Arc::new(logger)); }
+
+    #[cfg(feature = "slog")]
+    pub fn register_logger(&mut self, logger: slog::Logger) { self.register_log(logger); }
+
+    pub fn stats(&self) -> WatcherStats {
+        WatcherStats {
+            watches: self.paths.as_ref().map(HashMap::len).unwrap_or(1) + self.files.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Hands the watcher to a `Dispatcher` running `hook` for every created
+    /// path. Further hooks can be registered on the dispatcher.
+    pub fn on_create<F>(self, hook: F) -> Dispatcher
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        Dispatcher::new(self).on_create(hook)
+    }
+
+    pub fn on_modify<F>(self, hook: F) -> Dispatcher
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        Dispatcher::new(self).on_modify(hook)
+    }
+
+    pub fn on_delete<F>(self, hook: F) -> Dispatcher
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        Dispatcher::new(self).on_delete(hook)
+    }
+
+    pub fn on_any<F>(self, hook: F) -> Dispatcher
+        where F: FnMut(&WatchEvent) -> Control + 'static {
+        Dispatcher::new(self).on_any(hook)
+    }
+
+    /// Records the current state of the watched tree.
+    pub fn snapshot(&self) -> Result<Snapshot, WatcherError> {
+        let depth = self.depth();
+        let mut snapshot = Snapshot::new();
 
-                return Ok(true);
+        for dir in collect_dirs(&self.root, depth, &self.walk)? {
+            // Paths removed during the traversal are skipped.
+            let metadata = match fs::metadata(&dir) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            snapshot.insert(Path::new(&dir), &metadata);
+
+            for path in existing_files(&dir, &self.walk)? {
+                if let Ok(metadata) = fs::metadata(&path) {
+                    snapshot.insert(&path, &metadata);
+                }
             }
         }
+
+        Ok(snapshot)
+    }
+
+    /// Saves a snapshot to the state file, if one is configured. Also done
+    /// when the watcher is dropped.
+    pub fn save_state(&self) -> Result<(), WatcherError> {
+        match &self.state_file {
+            Some(state_file) => Ok(self.snapshot()?.save(state_file)?),
+            None => Ok(()),
+        }
     }
-}
 
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry.file_name()
-         .to_str()
-         .map(|s| s.starts_with("."))
-         .unwrap_or(false)
+    /// Returns a handle that can pause and resume this watcher from another thread.
+    pub fn pause_handle(&self) -> PauseHandle { self.pause.clone() }
+
+    /// Returns a guard to mark the paths this process is about to write,
+    /// see `WatcherBuilder::own_writes`.
+    pub fn write_guard(&self) -> WriteGuard { self.guard.clone() }
+
+    pub fn pause(&self) { self.pause.pause(); }
+
+    pub fn resume(&self, replay: ReplayPolicy) { self.pause.resume(replay); }
+
+    // The depth directories are watched up to, if limited.
+    fn depth(&self) -> Option<usize> {
+        match (self.degraded, self.max_depth) {
+            (true, depth) => Some(depth.map_or(1, |depth| depth.min(1))),
+            (false, depth) => depth,
+        }
+    }
+
+    fn kind_allowed(&self, event: &WatchEvent) -> bool {
+        if self.kinds.is_empty() || event.is_dir {
+            return true;
+        }
+
+        match event.kind {
+            EventKind::Created | EventKind::Modified => {
+                FileKind::sniff(&event.path).map(|kind| self.kinds.contains(&kind)).unwrap_or(true)
+            },
+            _ => true,
+        }
+    }
+
+    // How long until the watcher has to be read again even without new
+    // events: a storm in progress ends, a held file may have become stable,
+    // or missing files are looked for again.
+    pub(crate) fn next_timeout(&self) -> Option<Duration> {
+        let storm = self.storm.as_ref().and_then(StormDetector::remaining);
+        let poll = if self.missing_files.is_empty() { None } else { Some(MISSING_FILE_POLL) };
+        let stable = self.stabilizer.as_ref().and_then(Stabilizer::remaining);
+
+        [storm, poll, stable].iter().flatten().min().copied()
+    }
+
+    // Waits up to `timeout` for events to become available. Returns `false`
+    // early if interrupted by a signal.
+    fn readable(&self, timeout: Duration) -> Result<bool, WatcherError> { readable(self.notify.as_raw_fd(), timeout) }
+
+    // Reads one buffer of events, which may contain no changes at all. Waits
+    // for events at most `timeout`, or indefinitely if `None`.
+    fn read_batch(&mut self, timeout: Option<Duration>) -> Result<Vec<WatchEvent>, WatcherError> {
+        let batch = match self.read_changes(timeout) {
+            Err(e) if self.supervise => self.recover(e)?,
+            result => result?,
+        };
+
+        self.record(&batch);
+
+        Ok(batch)
+    }
+
+    fn read_changes(&mut self, timeout: Option<Duration>) -> Result<Vec<WatchEvent>, WatcherError> {
+        // Paused before waiting, e.g. while the previous batch was handled.
+        if self.pause.wait() == Some(ReplayPolicy::Discard) {
+            self.drain()?;
+        }
+
+        let wait = self.next_timeout().into_iter().chain(timeout).min();
+
+        let timed_out = match wait {
+            Some(wait) => !self.readable(wait)?,
+            None => false,
+        };
+
+        let mut batch = if timed_out { self.watch_missing_files() } else { self.read_events(true)? };
+
+        self.guard.apply(&mut batch, self.own_writes);
+
+        if let Some(stabilizer) = &mut self.stabilizer {
+            batch = stabilizer.absorb(batch);
+        }
+
+        // Paused while waiting.
+        if self.pause.wait() == Some(ReplayPolicy::Discard) {
+            batch.clear();
+            self.drain()?;
+        }
+
+        if timed_out && batch.is_empty() {
+            let root = self.root.clone();
+            let storm = self.storm.as_mut().and_then(|s| s.finish(&root));
+
+            if let Some(storm) = &storm {
+                watcher_info!(self, "Event storm ended after {} events", storm.storm.as_ref().map_or(0, |s| s.count));
+            }
+
+            return Ok(storm.into_iter().collect());
+        }
+
+        if let Some(filter) = &self.filter {
+            batch.retain(|event| filter.matches(event));
+        }
+
+        if let Some(content) = &mut self.content {
+            batch.retain(|event| content.changed(event));
+        }
+
+        if !self.kinds.is_empty() {
+            batch.retain(|event| self.kind_allowed(event));
+        }
+
+        if let Some(storm) = &mut self.storm {
+            let started = !storm.in_storm();
+
+            batch = storm.absorb(batch);
+
+            if started && storm.in_storm() {
+                watcher_warn!(self, "Event storm detected, collapsing events");
+            }
+        }
+
+        if self.with_metadata {
+            for event in batch.iter_mut().filter(|e| e.kind != EventKind::Deleted) {
+                event.metadata = Metadata::read(&event.path);
+            }
+        }
+
+        Ok(batch)
+    }
+
+    // Reads and drops what queued up, e.g. during a pause, so new directories
+    // are still watched.
+    fn drain(&mut self) -> Result<(), WatcherError> {
+        while self.readable(Duration::from_secs(0))? {
+            self.read_events(false)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_events(&mut self, blocking: bool) -> Result<Vec<WatchEvent>, WatcherError> {
+        // Taken out of `self` while the events borrow it.
+        let mut buffer = mem::take(&mut self.buffer);
+        let result = self.read_into(&mut buffer, blocking);
+
+        self.buffer = buffer;
+
+        result
+    }
+
+    fn read_into(&mut self, buffer: &mut Vec<u8>, blocking: bool) -> Result<Vec<WatchEvent>, WatcherError> {
+        let events = if blocking {
+            self.notify.read_events_blocking(buffer)?
+        } else {
+            self.notify.read_events(buffer)?
+        };
+        let mut batch = Vec::new();
+        let mut overflowed = false;
+        // Whether an event could not be handled, so changes may be missing.
+        let mut failed = false;
+        let mut used = 0;
+
+        for event in events {
+            // The kernel pads names with NULs to a multiple of the header size.
+            used += EVENT_HEADER_SIZE + event.name.map(|name| {
+                (name.len() + EVENT_HEADER_SIZE) / EVENT_HEADER_SIZE * EVENT_HEADER_SIZE
+            }).unwrap_or(0);
+
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                watcher_warn!(self, "Event queue overflowed, events were lost");
+
+                self.stats.overflows += 1;
+                overflowed = true;
+                continue;
+            }
+
+            let event = match &self.watcher_type {
+                _ if self.files.contains_key(&event.wd) => self.added_file_event(event),
+                WatcherType::FILE => Ok(Some(self.file_event(event))),
+                WatcherType::DIRECTORY => self.dir_event(event),
+            };
+
+            // The rest of the buffer is still handled.
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    watcher_warn!(self, "Failed to handle an event, reporting a rescan: {}", e);
+
+                    failed = true;
+                    continue;
+                },
+            };
+
+            if let Some(event) = &event {
+                self.stats.record(event);
+            }
+
+            batch.extend(event);
+        }
+
+        if overflowed || failed {
+            let event = self.rescan_event();
+
+            self.stats.record(&event);
+            batch.push(event);
+
+            if self.rescan_on_overflow {
+                match self.rescan() {
+                    Ok(events) => {
+                        for event in events {
+                            self.stats.record(&event);
+                            batch.push(event);
+                        }
+                    },
+                    Err(e) => watcher_warn!(self, "Failed to re-scan the tree: {}", e),
+                }
+            }
+        }
+
+        batch.extend(self.watch_missing_files());
+
+        self.grow_buffer(buffer, used);
+        self.maybe_log_stats();
+
+        Ok(batch)
+    }
+
+    // Doubles the buffer once reads have consistently filled it, i.e. may have
+    // left events in the queue that would have fit into a larger one.
+    fn grow_buffer(&mut self, buffer: &mut Vec<u8>, used: usize) {
+        if used + MAX_EVENT_SIZE <= buffer.len() {
+            self.full_reads = 0;
+            return;
+        }
+
+        self.full_reads += 1;
+
+        if self.full_reads >= FULL_READS && buffer.len() < self.max_buffer_size {
+            let size = (buffer.len() * 2).min(self.max_buffer_size);
+
+            watcher_info!(self, "Growing event buffer to {} bytes", size);
+
+            buffer.resize(size, 0);
+            self.full_reads = 0;
+        }
+    }
+
+    // Replaces the inotify instance after `error` and watches everything
+    // again, retrying with a growing delay. Returns the `Recovered` event, or
+    // the last error if every attempt failed. Errors only occur reading the
+    // queue, before any of its events are decoded, so none are lost here.
+    fn recover(&mut self, error: WatcherError) -> Result<Vec<WatchEvent>, WatcherError> {
+        let os_error = match &error {
+            WatcherError::Io(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(Vec::new()),
+            WatcherError::Io(e) => e.raw_os_error(),
+            WatcherError::Walk(_) => None,
+            _ => return Err(error),
+        };
+
+        let now = Instant::now();
+
+        while self.recoveries.front().is_some_and(|at| now.duration_since(*at) >= RECOVERY_WINDOW) {
+            self.recoveries.pop_front();
+        }
+
+        if self.recoveries.len() >= MAX_RECOVERIES {
+            watcher_warn!(self, "Recovered {} times within {:?}, giving up", MAX_RECOVERIES, RECOVERY_WINDOW);
+
+            return Err(error);
+        }
+
+        watcher_warn!(self, "Failed to read events, recovering: {}", error);
+
+        // The descriptor's number may already belong to another file.
+        let mut closed = os_error == Some(libc::EBADF);
+        let mut delay = RECOVERY_DELAY * (1 << self.recoveries.len());
+        let mut attempt = 1;
+
+        if !self.recoveries.is_empty() {
+            thread::sleep(delay);
+        }
+
+        loop {
+            match self.reinit(closed) {
+                Ok(()) => break,
+                Err(e) if attempt == MAX_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    watcher_warn!(self, "Failed to recover (attempt {} of {}): {}", attempt, MAX_ATTEMPTS, e);
+
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                    closed = false;
+                },
+            }
+        }
+
+        self.stats.recoveries += 1;
+        self.recoveries.push_back(Instant::now());
+        watcher_info!(self, "Recovered, watching {} directories", self.paths.as_ref().map_or(0, HashMap::len));
+
+        let event = WatchEvent {
+            kind: EventKind::Recovered,
+            ..self.rescan_event()
+        };
+
+        Ok(vec![event])
+    }
+
+    // Replaces the inotify instance, without closing the old one if it was
+    // `closed` already, and adds every watch to the new one.
+    fn reinit(&mut self, closed: bool) -> Result<(), WatcherError> {
+        let old = mem::replace(&mut self.notify, Inotify::init().map_err(WatcherError::Init)?);
+
+        if closed {
+            mem::forget(old);
+        }
+
+        match self.watcher_type {
+            WatcherType::FILE => {
+                error::add_watch(&mut self.notify, &self.root, self.watch_mask)?;
+            },
+            WatcherType::DIRECTORY => self.watch_tree()?,
+        }
+
+        // Kept until every file is watched again, for the next attempt.
+        let mut files = HashMap::new();
+        let mut missing = Vec::new();
+
+        for file in self.files.values() {
+            if file.is_file() {
+                files.insert(error::add_watch(&mut self.notify, file, file_mask())?, file.clone());
+            } else {
+                missing.push(file.clone());
+            }
+        }
+
+        self.files = files;
+        self.missing_files.extend(missing);
+
+        Ok(())
+    }
+
+    fn rescan_event(&self) -> WatchEvent {
+        let path = self.root.clone();
+
+        let (root, is_dir) = match self.watcher_type {
+            WatcherType::FILE => (path.parent().map(Path::to_path_buf).unwrap_or_default(), false),
+            WatcherType::DIRECTORY => (path.clone(), true),
+        };
+
+        WatchEvent {
+            kind: EventKind::Rescan,
+            path,
+            root,
+            is_dir,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    // Resyncs the watch set with the directories on disk, returning an event
+    // for each directory that appeared or disappeared. In `Traversal::HEURISTIC`
+    // mode new directories are left to the rebalance.
+    fn rescan(&mut self) -> Result<Vec<WatchEvent>, WatcherError> {
+        let depth = self.depth();
+        let dirs = collect_dirs(&self.root, depth, &self.walk)?;
+        let root = self.root.clone();
+        let mut events = Vec::new();
+
+        let paths = match &mut self.paths {
+            Some(paths) => paths,
+            None => return Ok(events),
+        };
+
+        let on_disk: HashSet<&PathBuf> = dirs.iter().collect();
+        let mut stale: Vec<(WatchDescriptor, PathBuf)> = paths.iter()
+            .filter(|(_, dir)| !on_disk.contains(dir))
+            .map(|(wd, dir)| (wd.clone(), dir.clone()))
+            .collect();
+        stale.sort_by(|a, b| a.1.cmp(&b.1));
+
+        for (wd, dir) in stale {
+            watcher_info!(self, "Directory deleted: {:?}", dir);
+
+            let _ = self.notify.rm_watch(wd.clone());
+            paths.remove(&wd);
+
+            events.push(WatchEvent {
+                kind: EventKind::Deleted,
+                path: dir,
+                root: root.clone(),
+                is_dir: true,
+                metadata: None,
+                storm: None,
+                origin: Origin::External,
+                timer: None,
+            });
+        }
+
+        if self.heuristic.is_some() {
+            self.rebalance()?;
+
+            return Ok(events);
+        }
+
+        let watched: HashSet<PathBuf> = paths.values().cloned().collect();
+
+        for dir in dirs.into_iter().filter(|dir| !watched.contains(dir)) {
+            match error::add_watch(&mut self.notify, &dir, self.watch_mask) {
+                Ok(wd) if paths.contains_key(&wd) => continue,
+                Ok(wd) => {
+                    watcher_info!(self, "Directory created: {:?}", dir);
+
+                    paths.insert(wd, dir.clone());
+                },
+                Err(WatcherError::WatchLimit { .. }) if self.limit_policy == LimitPolicy::Degrade => {
+                    watcher_warn!(self, "Watch limit reached, not watching: {:?}", dir);
+                    break;
+                },
+                // Removed again since the traversal.
+                Err(WatcherError::PathNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+
+            events.push(WatchEvent {
+                kind: EventKind::Created,
+                path: dir,
+                root: root.clone(),
+                is_dir: true,
+                metadata: None,
+                storm: None,
+                origin: Origin::External,
+                timer: None,
+            });
+        }
+
+        Ok(events)
+    }
+
+    fn maybe_log_stats(&mut self) {
+        match &mut self.log_stats {
+            Some((interval, last)) if last.elapsed() >= *interval => *last = Instant::now(),
+            _ => return,
+        }
+
+        let stats = self.stats();
+
+        watcher_info!(self, "Statistics: {} watches, {} created, {} modified, {} deleted, {} overflows",
+                      stats.watches, stats.created, stats.modified, stats.deleted, stats.overflows);
+    }
+
+    fn dir_event(&mut self, event: Event<&OsStr>) -> Result<Option<WatchEvent>, WatcherError> {
+        // Removed watches, e.g. after rolling back a traversal, are not changes.
+        if event.mask.contains(EventMask::IGNORED) {
+            if let Some(paths) = &mut self.paths {
+                paths.remove(&event.wd);
+            }
+
+            return Ok(None);
+        }
+
+        let kind = if event.mask.contains(EventMask::CREATE) {
+            EventKind::Created
+        } else if event.mask.contains(EventMask::DELETE) {
+            EventKind::Deleted
+        } else if event.mask.intersects(EventMask::MODIFY | EventMask::CLOSE_WRITE) {
+            EventKind::Modified
+        } else {
+            return Ok(None);
+        };
+
+        if self.walk.hidden == HiddenPolicy::ExcludeAll && event.name.map(is_hidden).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+        let parent = self.paths.as_ref()
+                         .and_then(|paths| paths.get(&event.wd))
+                         .unwrap_or(&self.root);
+        let path = match event.name {
+            Some(name) => Path::new(parent).join(name),
+            None => PathBuf::from(parent),
+        };
+
+        if is_dir && self.walk.ignored.contains(&path) {
+            return Ok(None);
+        }
+
+        // Not a change in itself, but ends one, see `WatcherBuilder::stabilize`.
+        if event.mask.contains(EventMask::CLOSE_WRITE) && !event.mask.contains(EventMask::MODIFY) {
+            if let Some(stabilizer) = &mut self.stabilizer {
+                stabilizer.close(path);
+            }
+
+            return Ok(None);
+        }
+
+        // Reported by the file's own watch, also if reached through another path.
+        if !is_dir && !self.file_ids.is_empty() && file_id(&path).is_some_and(|id| self.file_ids.contains_key(&id)) {
+            return Ok(None);
+        }
+
+        match (kind, is_dir) {
+            (EventKind::Created, true) => watcher_info!(self, "Directory created: {:?}", path),
+            (EventKind::Created, false) => watcher_info!(self, "File created: {:?}", path),
+            (EventKind::Deleted, true) => watcher_info!(self, "Directory deleted: {:?}", path),
+            (EventKind::Deleted, false) => watcher_info!(self, "File deleted: {:?}", path),
+            (EventKind::Modified, true) => watcher_info!(self, "Directory modified: {:?}", path),
+            (EventKind::Modified, false) => watcher_info!(self, "File modified: {:?}", path),
+            (EventKind::Rescan, _) | (EventKind::Storm, _) | (EventKind::Timer, _) | (EventKind::Recovered, _) => {},
+        }
+
+        if kind == EventKind::Created && is_dir {
+            self.watch_new_dir(&event.wd, &path)?;
+        }
+
+        self.record_activity(&event.wd)?;
+
+        Ok(Some(WatchEvent {
+            kind,
+            path,
+            root: self.root.clone(),
+            is_dir,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }))
+    }
+
+    fn watch_new_dir(&mut self, parent: &WatchDescriptor, path: &Path) -> Result<(), WatcherError> {
+        let depth = path.strip_prefix(&self.root).map(|p| p.components().count()).unwrap_or(0);
+        let too_deep = self.depth().is_some_and(|max| depth > max);
+
+        let paths = match &mut self.paths {
+            Some(paths) if paths.contains_key(parent) => paths,
+            _ => return Ok(()),
+        };
+
+        let in_budget = match &self.heuristic {
+            Some(heuristic) => paths.len().saturating_sub(1) < heuristic.budget,
+            None => true,
+        };
+
+        if excluded(path, &self.walk) || !in_budget || too_deep {
+            return Ok(());
+        }
+
+        watcher_info!(self, "Watching new directory: {:?}", path);
+
+        match error::add_watch(&mut self.notify, path, self.watch_mask) {
+            // The same directory, reached through a symlink created meanwhile.
+            Ok(wd) if paths.contains_key(&wd) => watcher_info!(self, "Already watched as {:?}: {:?}", paths[&wd], path),
+            Ok(wd) => { paths.insert(wd, path.to_path_buf()); },
+            Err(WatcherError::WatchLimit { .. }) if self.limit_policy == LimitPolicy::Degrade => {
+                watcher_warn!(self, "Watch limit reached, not watching: {:?}", path);
+            },
+            // Deleted again, which is reported by its parent.
+            Err(WatcherError::PathNotFound(_)) => watcher_info!(self, "Directory deleted before it was watched: {:?}", path),
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    fn added_file_event(&mut self, event: Event<&OsStr>) -> Result<Option<WatchEvent>, WatcherError> {
+        let path = self.files[&event.wd].clone();
+
+        if event.mask.contains(EventMask::MODIFY) {
+            watcher_info!(self, "File modified: {:?}", path);
+
+            return Ok(Some(self.added_file_change(EventKind::Modified, path)));
+        }
+
+        if !event.mask.intersects(EventMask::MOVE_SELF | EventMask::DELETE_SELF | EventMask::IGNORED) {
+            return Ok(None);
+        }
+
+        // The watch follows the inode, which no longer is the file at `path`.
+        self.files.remove(&event.wd);
+
+        if event.mask.contains(EventMask::MOVE_SELF) {
+            let _ = self.notify.rm_watch(event.wd.clone());
+        }
+
+        if path.is_file() {
+            watcher_info!(self, "File replaced: {:?}", path);
+
+            let wd = error::add_watch(&mut self.notify, &path, file_mask())?;
+            self.files.insert(wd, path.clone());
+
+            return Ok(Some(self.added_file_change(EventKind::Modified, path)));
+        }
+
+        watcher_info!(self, "File removed: {:?}", path);
+
+        self.missing_files.push(path.clone());
+
+        Ok(Some(self.added_file_change(EventKind::Deleted, path)))
+    }
+
+    // Watches the files added with `add_file` that reappeared, and returns an
+    // event for each.
+    // Files that cannot be watched yet are tried again on the next call.
+    fn watch_missing_files(&mut self) -> Vec<WatchEvent> {
+        let (found, missing) = mem::take(&mut self.missing_files).into_iter().partition(|file| file.is_file());
+        let mut events = Vec::new();
+
+        self.missing_files = missing;
+
+        for file in found {
+            let wd = match error::add_watch(&mut self.notify, &file, file_mask()) {
+                Ok(wd) => wd,
+                Err(e) => {
+                    watcher_warn!(self, "Failed to watch file {:?}: {}", file, e);
+
+                    self.missing_files.push(file);
+                    continue;
+                },
+            };
+
+            watcher_info!(self, "File created: {:?}", file);
+
+            self.files.insert(wd, file.clone());
+
+            let event = self.added_file_change(EventKind::Created, file);
+
+            self.stats.record(&event);
+            events.push(event);
+        }
+
+        events
+    }
+
+    // Files outside the tree are relative to their directory.
+    fn added_file_change(&self, kind: EventKind, path: PathBuf) -> WatchEvent {
+        let root = match self.watcher_type {
+            WatcherType::DIRECTORY if path.starts_with(&self.root) => self.root.clone(),
+            _ => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+
+        WatchEvent {
+            kind,
+            path,
+            root,
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+
+    fn file_event(&mut self, event: Event<&OsStr>) -> WatchEvent {
+        let kind = if event.mask.contains(EventMask::MODIFY) {
+            watcher_info!(self, "File modified");
+
+            EventKind::Modified
+        } else if event.mask.contains(EventMask::IGNORED) {
+            watcher_info!(self, "File removed");
+
+            EventKind::Deleted
+        } else {
+            watcher_info!(self, "Unexpected event: {:?}", event.name);
+
+            EventKind::Modified
+        };
+
+        let path = self.root.clone();
+
+        WatchEvent {
+            kind,
+            root: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            path,
+            is_dir: false,
+            metadata: None,
+            storm: None,
+            origin: Origin::External,
+            timer: None,
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_state() {
+            watcher_warn!(self, "Failed to save state: {}", e);
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    /// The inotify file descriptor, readable when events are available. Note
+    /// that `next_event` may also return events read earlier.
+    fn as_raw_fd(&self) -> RawFd { self.notify.as_raw_fd() }
+}
+
+// Waits up to `timeout` for `fd` to become readable. Returns `false` early if
+// interrupted by a signal.
+pub(crate) fn readable(fd: RawFd, timeout: Duration) -> Result<bool, WatcherError> {
+    let mut fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+    match unsafe { libc::poll(&mut fd, 1, millis) } {
+        -1 => match io::Error::last_os_error() {
+            ref e if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+            e => Err(WatcherError::Io(e)),
+        },
+        n => Ok(n > 0),
+    }
+}
+
+/// Reads the per-user inotify watch limit from procfs.
+pub fn max_user_watches() -> Option<usize> {
+    fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
+}
+
+/// Counts the inotify watches of all processes of the current user, by
+/// reading the `inotify wd:` lines of their file descriptors in procfs.
+pub fn watches_in_use() -> Option<usize> {
+    let uid = unsafe { libc::getuid() };
+    let mut count = 0;
+
+    for process in fs::read_dir("/proc").ok()? {
+        // Only the numeric entries are processes, "self" would count twice.
+        let process = match process {
+            Ok(process) if process.file_name().to_str().is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())) => process.path(),
+            _ => continue,
+        };
+
+        let owned = fs::metadata(&process).map(|m| m.uid() == uid).unwrap_or(false);
+        let fds = match fs::read_dir(process.join("fdinfo")) {
+            Ok(fds) if owned => fds,
+            _ => continue,
+        };
+
+        for fd in fds.flatten() {
+            if let Ok(info) = fs::read_to_string(fd.path()) {
+                count += info.lines().filter(|line| line.starts_with("inotify wd:")).count();
+            }
+        }
+    }
+
+    Some(count)
+}
+
+fn collect_dirs(path: &Path, max_depth: Option<usize>, walk: &WalkOptions)
+    -> Result<Vec<PathBuf>, WatcherError> {
+    let mut dirs = Vec::new();
+
+    walk_dirs(path, max_depth, walk, &mut HashSet::new(), &mut dirs)?;
+
+    Ok(dirs)
+}
+
+// Collects the directories below `path`. Directories are identified by
+// (device, inode), so ones reachable through several symlinks are only
+// collected once.
+fn walk_dirs(path: &Path, max_depth: Option<usize>, walk: &WalkOptions,
+             seen: &mut HashSet<(u64, u64)>, dirs: &mut Vec<PathBuf>) -> Result<(), WatcherError> {
+    let mut walker = WalkDir::new(path).follow_links(walk.symlinks == SymlinkPolicy::Follow);
+
+    if let Some(depth) = max_depth {
+        walker = walker.max_depth(depth);
+    }
+
+    let top_level_link = |e: &DirEntry| {
+        walk.symlinks == SymlinkPolicy::FollowTopLevel && e.depth() == 1 && e.path_is_symlink()
+    };
+
+    for entry in walker.into_iter()
+                       .filter_entry(|e| e.depth() == 0 || (!excluded(e.path(), walk) &&
+                                                            (e.file_type().is_dir() || top_level_link(e)))) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // A symlink pointing back to one of its ancestors; walkdir does not descend into it.
+            Err(ref e) if e.loop_ancestor().is_some() => continue,
+            Err(e) => return Err(WatcherError::from(e)),
+        };
+
+        if top_level_link(&entry) {
+            if fs::metadata(entry.path()).map(|m| m.is_dir()).unwrap_or(false) {
+                let depth = max_depth.map(|depth| depth - 1);
+
+                let walk = WalkOptions { symlinks: SymlinkPolicy::NoFollow, ..walk.clone() };
+
+                walk_dirs(entry.path(), depth, &walk, seen, dirs)?;
+            }
+
+            continue;
+        }
+
+        // Directories removed during the traversal are skipped.
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+
+        dirs.push(entry.into_path());
+    }
+
+    Ok(())
+}
+
+// Identifies a file by the (device, inode) of its directory and its name,
+// which unlike its own inode survive atomic saves.
+type FileId = (u64, u64, OsString);
+
+fn file_id(path: &Path) -> Option<FileId> {
+    let name = path.file_name()?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let metadata = fs::metadata(dir).ok()?;
+
+    Some((metadata.dev(), metadata.ino(), name.to_os_string()))
+}
+
+// Lists the files directly inside `dir` that events would be reported for.
+fn existing_files(dir: &Path, walk: &WalkOptions) -> Result<Vec<PathBuf>, WatcherError> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().map(is_hidden).unwrap_or(false);
+
+        if path.is_file() && !(hidden && walk.hidden == HiddenPolicy::ExcludeAll) {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+// The events of files added with `Watcher::add_file`.
+fn file_mask() -> WatchMask { WatchMask::MODIFY | WatchMask::MOVE_SELF | WatchMask::DELETE_SELF }
+
+// Whether a directory is skipped by the traversal.
+fn excluded(path: &Path, walk: &WalkOptions) -> bool {
+    let hidden = path.file_name().map(is_hidden).unwrap_or(false);
+
+    (walk.hidden != HiddenPolicy::IncludeAll && hidden) || walk.ignored.iter().any(|dir| dir == path)
+}
+
+fn is_hidden(name: &OsStr) -> bool { name.as_bytes().first() == Some(&b'.') }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::FileType;
+    use std::env;
+    use std::process;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("aa-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tree/sub")).unwrap();
+
+        dir
+    }
+
+    // Makes reading the watcher's descriptor fail, by replacing it with
+    // /dev/null opened for writing only. Closing it instead would let the
+    // number be reused by another test, before the watcher closes it again.
+    fn break_descriptor(watcher: &Watcher) {
+        let null = fs::OpenOptions::new().write(true).open("/dev/null").unwrap();
+
+        assert_eq!(unsafe { libc::dup2(null.as_raw_fd(), watcher.as_raw_fd()) }, watcher.as_raw_fd());
+    }
+
+    fn drain(watcher: &mut Watcher) -> Result<Vec<(EventKind, PathBuf)>, WatcherError> {
+        let mut events = Vec::new();
+
+        while let Some(event) = watcher.next_event_timeout(Duration::from_millis(200))? {
+            events.push((event.kind, event.path));
+        }
+
+        Ok(events)
+    }
+
+    #[test]
+    fn symlink_policies_follow_links_as_configured_without_looping() {
+        let dir = scratch("symlinks");
+        let tree = dir.join("tree");
+        fs::create_dir_all(dir.join("outside/deep")).unwrap();
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(&tree, tree.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), tree.join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("outside/nested")).unwrap();
+
+        let plan = |policy| {
+            let builder = WatcherBuilder::new(&tree).traversal(Traversal::RECURSIVE).symlinks(policy);
+            let mut dirs: Vec<String> = builder.plan().unwrap().dirs.iter()
+                .map(|dir| dir.strip_prefix(&tree).unwrap().display().to_string())
+                .collect();
+
+            dirs.sort();
+            dirs
+        };
+
+        assert_eq!(plan(SymlinkPolicy::NoFollow), ["", "sub"]);
+        assert_eq!(plan(SymlinkPolicy::FollowTopLevel), ["", "out", "out/deep", "sub"]);
+        // sub/loop leads back to the root, which is already watched.
+        assert_eq!(plan(SymlinkPolicy::Follow), ["", "out", "out/deep", "out/nested", "sub"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_policies_skip_dot_dirs_and_files_as_configured() {
+        let dir = scratch("hidden");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join(".git")).unwrap();
+
+        let builder = |policy| WatcherBuilder::new(&tree).traversal(Traversal::RECURSIVE).hidden(policy);
+
+        assert_eq!(builder(HiddenPolicy::IncludeAll).plan().unwrap().watches(), 3);
+        assert_eq!(builder(HiddenPolicy::ExcludeDirs).plan().unwrap().watches(), 2);
+
+        let mut include = builder(HiddenPolicy::ExcludeDirs).build().unwrap();
+        let mut exclude = builder(HiddenPolicy::ExcludeAll).build().unwrap();
+        fs::write(tree.join(".env"), "").unwrap();
+        fs::write(tree.join(".git/index"), "").unwrap();
+        fs::write(tree.join("shown"), "").unwrap();
+
+        let paths = |watcher: &mut Watcher| -> Vec<PathBuf> {
+            let mut paths: Vec<PathBuf> = drain(watcher).unwrap().into_iter().map(|(_, path)| path).collect();
+            paths.dedup();
+            paths
+        };
+
+        assert_eq!(paths(&mut include), [tree.join(".env"), tree.join("shown")]);
+        assert_eq!(paths(&mut exclude), [tree.join("shown")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_is_attached_only_when_asked_for() {
+        let dir = scratch("metadata");
+        let tree = dir.join("tree");
+
+        let mut with = WatcherBuilder::new(&tree).with_metadata(true).build().unwrap();
+        let mut without = WatcherBuilder::new(&tree).build().unwrap();
+        fs::write(tree.join("file"), "hello").unwrap();
+
+        let event = with.next_event_timeout(Duration::from_millis(200)).unwrap().unwrap();
+        let metadata = event.metadata.unwrap();
+        assert_eq!(metadata.len, 5);
+        assert_eq!(metadata.file_type, FileType::File);
+        assert!(metadata.modified.is_some());
+
+        let event = without.next_event_timeout(Duration::from_millis(200)).unwrap().unwrap();
+        assert_eq!(event.metadata, None);
+
+        drain(&mut with).unwrap();
+        fs::remove_file(tree.join("file")).unwrap();
+
+        let event = with.next_event_timeout(Duration::from_millis(200)).unwrap().unwrap();
+        assert_eq!(event.kind, EventKind::Deleted);
+        assert_eq!(event.metadata, None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filter_kind_passes_only_files_of_the_given_kinds() {
+        let dir = scratch("kinds");
+        let tree = dir.join("tree");
+
+        let mut watcher = WatcherBuilder::new(&tree).filter_kind(FileKind::Image).build().unwrap();
+        fs::write(tree.join("notes"), "text").unwrap();
+        fs::write(tree.join("logo"), b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let mut paths: Vec<PathBuf> = drain(&mut watcher).unwrap().into_iter().map(|(_, path)| path).collect();
+        paths.dedup();
+
+        assert_eq!(paths, [tree.join("logo")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn plan_lists_the_dirs_a_build_would_watch() {
+        let dir = scratch("plan");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("sub/deeper")).unwrap();
+
+        let builder = || WatcherBuilder::new(&tree).traversal(Traversal::RECURSIVE);
+
+        let plan = builder().plan().unwrap();
+        assert_eq!(plan.dirs, [tree.clone(), tree.join("sub"), tree.join("sub/deeper")]);
+        assert_eq!(plan.watches(), builder().build().unwrap().stats().watches);
+
+        assert_eq!(builder().max_depth(1).plan().unwrap().watches(), 2);
+
+        let fits = |limit, in_use| Plan { limit, in_use, ..plan.clone() }.fits();
+        assert_eq!(fits(None, Some(0)), None);
+        assert_eq!(fits(Some(10), None), Some(true));
+        assert_eq!(fits(Some(10), Some(7)), Some(true));
+        assert_eq!(fits(Some(10), Some(8)), Some(false));
+        assert_eq!(fits(Some(10), Some(20)), Some(false));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_reached_through_several_paths_are_watched_once() {
+        let dir = scratch("identity");
+        let tree = dir.join("tree");
+        fs::write(tree.join("sub/file"), "").unwrap();
+        std::os::unix::fs::symlink(tree.join("sub"), dir.join("link")).unwrap();
+
+        let mut watcher = WatcherBuilder::new(&tree).traversal(Traversal::RECURSIVE).build().unwrap();
+        watcher.add_file(dir.join("link/file")).unwrap();
+        watcher.add_file(tree.join("sub/file")).unwrap();
+
+        assert_eq!(watcher.stats().watches, 3);
+
+        fs::write(tree.join("sub/file"), "changed").unwrap();
+
+        let mut paths: Vec<PathBuf> = drain(&mut watcher).unwrap().into_iter().map(|(_, path)| path).collect();
+        paths.dedup();
+
+        // Reported under the path it was first added as, not by the tree.
+        assert_eq!(paths, [dir.join("link/file")]);
+
+        assert!(watcher.remove_file(tree.join("sub/file")));
+        assert_eq!(watcher.stats().watches, 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn own_writes_are_suppressed_when_configured() {
+        let dir = scratch("guard");
+        let tree = dir.join("tree");
+
+        let mut watcher = WatcherBuilder::new(&tree).own_writes(OwnWrites::Suppress).build().unwrap();
+        let guard = watcher.write_guard();
+
+        guard.write(tree.join("generated"), || fs::write(tree.join("generated"), "")).unwrap();
+        fs::write(tree.join("edited"), "").unwrap();
+
+        let mut paths: Vec<PathBuf> = drain(&mut watcher).unwrap().into_iter().map(|(_, path)| path).collect();
+        paths.dedup();
+
+        assert_eq!(paths, [tree.join("edited")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reinit_watches_dirs_and_added_files_again() {
+        let dir = scratch("reinit");
+        let extra = dir.join("extra");
+        fs::write(&extra, "").unwrap();
+
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).build().unwrap();
+        watcher.add_file(&extra).unwrap();
+
+        watcher.reinit(false).unwrap();
+
+        fs::write(dir.join("tree/sub/a"), "a").unwrap();
+        fs::write(&extra, "b").unwrap();
+
+        let events = drain(&mut watcher).unwrap();
+
+        assert!(events.contains(&(EventKind::Created, dir.join("tree/sub/a"))), "{:?}", events);
+        assert!(events.contains(&(EventKind::Modified, extra)), "{:?}", events);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stats_count_the_watches_of_added_files() {
+        let dir = scratch("stats");
+        let extra = dir.join("extra");
+        fs::write(&extra, "").unwrap();
+
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).build().unwrap();
+        assert_eq!(watcher.stats().watches, 2);
+
+        watcher.add_file(&extra).unwrap();
+        assert_eq!(watcher.stats().watches, 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn supervised_watcher_recovers_from_a_broken_descriptor() {
+        let dir = scratch("recover");
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).supervise(true).build().unwrap();
+
+        break_descriptor(&watcher);
+
+        assert_eq!(drain(&mut watcher).unwrap(), vec![(EventKind::Recovered, dir.join("tree"))]);
+
+        fs::write(dir.join("tree/sub/a"), "a").unwrap();
+
+        assert!(drain(&mut watcher).unwrap().contains(&(EventKind::Created, dir.join("tree/sub/a"))));
+        assert_eq!(watcher.stats().recoveries, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsupervised_watcher_fails_on_a_broken_descriptor() {
+        let dir = scratch("unsupervised");
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).build().unwrap();
+
+        break_descriptor(&watcher);
+
+        assert!(drain(&mut watcher).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resuming_with_discard_drops_everything_queued_during_the_pause() {
+        let dir = scratch("discard");
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).filter(Filter::ext("rs")).build().unwrap();
+        let pause = watcher.pause_handle();
+
+        pause.pause();
+
+        // Filtered out entirely, so draining must not stop at them.
+        for i in 0..100 {
+            fs::write(dir.join(format!("tree/{}.txt", i)), "").unwrap();
+        }
+        fs::write(dir.join("tree/during.rs"), "").unwrap();
+
+        let resume = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            pause.resume(ReplayPolicy::Discard);
+        });
+
+        let first = watcher.next_event_timeout(Duration::from_millis(500)).unwrap();
+
+        resume.join().unwrap();
+        fs::write(dir.join("tree/after.rs"), "").unwrap();
+
+        assert_eq!(first, None);
+        assert_eq!(drain(&mut watcher).unwrap()[0], (EventKind::Created, dir.join("tree/after.rs")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn supervised_watcher_gives_up_on_recurring_errors() {
+        let dir = scratch("give-up");
+        let mut watcher = WatcherBuilder::new(dir.join("tree")).supervise(true).build().unwrap();
+
+        for _ in 0..MAX_RECOVERIES {
+            break_descriptor(&watcher);
+
+            assert_eq!(drain(&mut watcher).unwrap().len(), 1);
+        }
+
+        break_descriptor(&watcher);
+
+        assert!(drain(&mut watcher).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }